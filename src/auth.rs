@@ -0,0 +1,319 @@
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::{web, FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::Database;
+use crate::utils;
+use crate::utils::http_errors::AnyhowHTTPError;
+use crate::utils::logging_util::LoggableResult;
+
+/// `last_used_at` is only written this often, so that e.g. a Shortcut
+/// polling the status doesn't write to the database on every request
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+/// What a client token may do. `Admin` may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scope {
+    /// job progress, printer listing, history and stats
+    ReadStatus,
+    /// loading and unloading filament
+    Filament,
+    /// starting, pausing, resuming and cancelling prints
+    JobControl,
+    /// managing tokens
+    Admin,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadStatus => "readStatus",
+            Self::Filament => "filament",
+            Self::JobControl => "jobControl",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// For Siri, as in "Token phone isn't allowed to ..."
+    fn spoken(self) -> &'static str {
+        match self {
+            Self::ReadStatus => "check on the printer",
+            Self::Filament => "load or unload filament",
+            Self::JobControl => "control print jobs",
+            Self::Admin => "manage tokens",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "readStatus" => Some(Self::ReadStatus),
+            "filament" => Some(Self::Filament),
+            "jobControl" => Some(Self::JobControl),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenInfo {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Only returned when the token is created, as just its hash is stored
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewToken {
+    #[serde(flatten)]
+    pub info: TokenInfo,
+    pub token: String,
+}
+
+/// The caller of a route, as identified by the token in `X-Api-Key`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientToken {
+    /// `None` for the admin token from the config
+    pub id: Option<i64>,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ClientToken {
+    pub fn require(&self, scope: Scope) -> Result<(), AnyhowHTTPError> {
+        if self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin) {
+            return Ok(());
+        }
+        Err(AnyhowHTTPError::Forbidden403(format!(
+            "Token {} isn't allowed to {}",
+            self.name,
+            scope.spoken()
+        )))
+    }
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn split_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split(',').filter_map(Scope::parse).collect()
+}
+
+/// Client tokens handed out by this server, so phones never see the printers' own keys
+pub struct TokenStore {
+    db: Arc<Database>,
+    /// of the admin token from the config, for creating the first tokens.
+    /// Only digests are compared, so the time that takes says nothing about the token.
+    admin_token_hash: Option<String>,
+}
+
+impl TokenStore {
+    pub fn new(db: Arc<Database>, admin_token: Option<String>) -> Self {
+        Self {
+            db,
+            admin_token_hash: admin_token.as_deref().map(hash),
+        }
+    }
+
+    pub fn create(&self, name: &str, scopes: &[Scope]) -> anyhow::Result<NewToken> {
+        let mut bytes = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        let mut scopes = scopes.to_vec();
+        scopes.sort();
+        scopes.dedup();
+        let created_at = Utc::now();
+
+        let id = self.db.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO tokens (name, token_hash, scopes, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![name, hash(&token), join_scopes(&scopes), created_at],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })?;
+
+        Ok(NewToken {
+            info: TokenInfo {
+                id,
+                name: name.to_string(),
+                scopes,
+                created_at,
+                last_used_at: None,
+                revoked_at: None,
+            },
+            token,
+        })
+    }
+
+    /// Revoked tokens are listed too, oldest first
+    pub fn list(&self) -> anyhow::Result<Vec<TokenInfo>> {
+        self.db.with_conn(|conn| {
+            let mut statement = conn.prepare(
+                "SELECT id, name, scopes, created_at, last_used_at, revoked_at
+                FROM tokens ORDER BY id",
+            )?;
+            let rows = statement.query_map([], |row| {
+                Ok(TokenInfo {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    scopes: split_scopes(&row.get::<_, String>(2)?),
+                    created_at: row.get(3)?,
+                    last_used_at: row.get(4)?,
+                    revoked_at: row.get(5)?,
+                })
+            })?;
+            rows.collect()
+        })
+    }
+
+    /// `false` if there is no such token or it was already revoked
+    pub fn revoke(&self, id: i64) -> anyhow::Result<bool> {
+        let changed = self.db.with_conn(|conn| {
+            conn.execute(
+                "UPDATE tokens SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL",
+                params![Utc::now(), id],
+            )
+        })?;
+        Ok(changed > 0)
+    }
+
+    /// `None` for unknown and revoked tokens
+    pub fn authenticate(&self, token: &str) -> anyhow::Result<Option<ClientToken>> {
+        let token_hash = hash(token);
+        if self.admin_token_hash.as_ref() == Some(&token_hash) {
+            return Ok(Some(ClientToken {
+                id: None,
+                name: "admin".to_string(),
+                scopes: vec![Scope::Admin],
+            }));
+        }
+
+        self.db.with_conn(|conn| {
+            let found = conn
+                .query_row(
+                    "SELECT id, name, scopes, last_used_at FROM tokens
+                    WHERE token_hash = ?1 AND revoked_at IS NULL",
+                    params![token_hash],
+                    |row| {
+                        let token = ClientToken {
+                            id: Some(row.get(0)?),
+                            name: row.get(1)?,
+                            scopes: split_scopes(&row.get::<_, String>(2)?),
+                        };
+                        Ok((token, row.get::<_, Option<DateTime<Utc>>>(3)?))
+                    },
+                )
+                .optional()?;
+
+            let now = Utc::now();
+            if let Some((ClientToken { id: Some(id), .. }, last_used_at)) = &found {
+                let recent = last_used_at.is_some_and(|at| {
+                    now - at < chrono::Duration::seconds(LAST_USED_RESOLUTION_SECS)
+                });
+                if !recent {
+                    conn.execute(
+                        "UPDATE tokens SET last_used_at = ?1 WHERE id = ?2",
+                        params![now, id],
+                    )?;
+                }
+            }
+            Ok(found.map(|(token, _)| token))
+        })
+    }
+}
+
+impl FromRequest for ClientToken {
+    type Error = AnyhowHTTPError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(client_token(req))
+    }
+}
+
+fn client_token(req: &HttpRequest) -> Result<ClientToken, AnyhowHTTPError> {
+    let store = req.app_data::<web::Data<TokenStore>>().ok_or_else(|| {
+        AnyhowHTTPError::InternalServerError500("Token store not configured".to_string())
+    })?;
+
+    let token = utils::get_api_key(req)?;
+    store
+        .authenticate(token)
+        .log_error()?
+        .ok_or_else(|| AnyhowHTTPError::Unauthorized401("Unknown or revoked token".to_string()))
+        .log_warn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let store = TokenStore::new(
+            Arc::new(Database::open_in_memory().unwrap()),
+            Some("bootstrap".to_string()),
+        );
+
+        let admin = store.authenticate("bootstrap").unwrap().unwrap();
+        assert!(admin.require(Scope::Filament).is_ok());
+        assert!(store.authenticate("wrong").unwrap().is_none());
+
+        let phone = store
+            .create(
+                "phone",
+                &[Scope::JobControl, Scope::ReadStatus, Scope::ReadStatus],
+            )
+            .unwrap();
+        assert_eq!(
+            phone.info.scopes,
+            vec![Scope::ReadStatus, Scope::JobControl]
+        );
+        assert_eq!(phone.token.len(), 48);
+
+        let client = store.authenticate(&phone.token).unwrap().unwrap();
+        assert_eq!(client.id, Some(phone.info.id));
+        assert!(client.require(Scope::JobControl).is_ok());
+        assert!(matches!(
+            client.require(Scope::Filament),
+            Err(AnyhowHTTPError::Forbidden403(_))
+        ));
+
+        let listed = store.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].last_used_at.is_some());
+        store.authenticate(&phone.token).unwrap().unwrap();
+        assert_eq!(
+            store.list().unwrap()[0].last_used_at,
+            listed[0].last_used_at,
+            "not written again within a minute"
+        );
+
+        assert!(store.revoke(phone.info.id).unwrap());
+        assert!(!store.revoke(phone.info.id).unwrap());
+        assert!(store.authenticate(&phone.token).unwrap().is_none());
+        assert!(store.list().unwrap()[0].revoked_at.is_some());
+    }
+}
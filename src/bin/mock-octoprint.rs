@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{info, LevelFilter};
+use printer_actions::mock_octoprint::{self, MockOctoPrint};
+use simple_logger::SimpleLogger;
+
+/// Serves a fake OctoPrint, configured with env variables:
+/// `MOCK_ADDRESS` (default `127.0.0.1:5002`), `MOCK_API_KEY` (default `mock`)
+/// and `MOCK_SPEED`, how many simulated seconds pass per real second (default 1).
+#[actix_web::main]
+async fn main() -> anyhow::Result<()> {
+    SimpleLogger::new()
+        .with_level(LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    let addr: SocketAddr = std::env::var("MOCK_ADDRESS")
+        .unwrap_or_else(|_| "127.0.0.1:5002".to_string())
+        .parse()?;
+    let api_key = std::env::var("MOCK_API_KEY").unwrap_or_else(|_| "mock".to_string());
+    let speed: f64 = match std::env::var("MOCK_SPEED") {
+        Ok(speed) => speed.parse()?,
+        Err(_) => 1.,
+    };
+
+    let (addr, server) =
+        mock_octoprint::start(Arc::new(MockOctoPrint::new(&api_key, speed)), addr)?;
+    info!(
+        "Mock OctoPrint running, use OCTOPRINT_URL=http://{}/api API_KEY={}",
+        addr, api_key
+    );
+    server.await?;
+
+    Ok(())
+}
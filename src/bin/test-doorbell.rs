@@ -1,13 +1,25 @@
 use printer_actions::{
-    remote::notify_homebridge::NotifyHomebridge, traits::notify_trait::Notifier,
+    config::Config,
+    events::{PrintInfo, PrinterEvent},
+    remote::notify_homebridge::NotifyHomebridge,
+    traits::notify_trait::Notifier,
 };
 
 fn main() {
+    dotenv::dotenv().ok();
+    let config = Config::load().unwrap();
+
     let web_client = reqwest::Client::new();
-    let notifier = NotifyHomebridge::new(web_client);
+    let homebridge = config.homebridge.expect("homebridge.url is not configured");
+    let notifier = NotifyHomebridge::new(web_client, &homebridge.url);
+
+    let event = PrinterEvent::PrintFinished(PrintInfo {
+        file_name: Some("test-doorbell.gcode".to_string()),
+        ..Default::default()
+    });
 
     tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(notifier.notify())
+        .block_on(notifier.notify(&config.default_printer, &event))
         .unwrap();
 }
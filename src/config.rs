@@ -0,0 +1,1234 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, Url};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::events::EventKind;
+use crate::filaments::{FilamentProfile, FilamentProfiles, HotEndTemperature};
+use crate::utils::time_utils::TimeWindow;
+
+/// Path used when `PRINTER_ACTIONS_CONFIG` is not set
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Path used when neither `storage.database` nor `DATABASE_PATH` is set
+pub const DEFAULT_DATABASE_PATH: &str = "data/printer-actions.db";
+
+/// How long a confirmation token is valid when `confirm.timeout_seconds` is not set
+pub const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What Marlin uses out of the box
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Missing config value `{key}`")]
+    Missing { key: String },
+    #[error("Invalid config value `{key}`: {message}")]
+    Invalid { key: String, message: String },
+}
+
+impl ConfigError {
+    fn invalid(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::Invalid {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// The config file as written on disk. Everything is optional here
+/// so that env variables can fill in the gaps before validation.
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    server: RawServerConfig,
+    default_printer: Option<String>,
+    printers: BTreeMap<String, RawPrinterConfig>,
+    homebridge: RawHomebridgeConfig,
+    webhooks: Vec<RawWebhookConfig>,
+    routes: Vec<RouteConfig>,
+    quiet_hours: Option<QuietHoursConfig>,
+    storage: RawStorageConfig,
+    auth: RawAuthConfig,
+    confirm: RawConfirmConfig,
+    heating: RawHeatingConfig,
+    filaments: Vec<RawFilamentConfig>,
+    spools: RawSpoolsConfig,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawServerConfig {
+    bind_address: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawPrinterConfig {
+    backend: Backend,
+    base_url: Option<String>,
+    /// `read_key` is the old name, from when clients used the printer's key too
+    #[serde(alias = "read_key")]
+    api_key: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    serial_port: Option<String>,
+    baud_rate: Option<u32>,
+    park: Option<ParkPosition>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawHomebridgeConfig {
+    url: Option<String>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawStorageConfig {
+    database: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawAuthConfig {
+    admin_token: Option<String>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfirmConfig {
+    cancel: ConfirmPolicy,
+    restart: ConfirmPolicy,
+    timeout_seconds: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawHeatingConfig {
+    timeout_seconds: Option<u64>,
+    stall_seconds: Option<u64>,
+    min_rise: Option<f64>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RawSpoolsConfig {
+    low_grams: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RawFilamentConfig {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    nozzle_temperature: u32,
+    bed_temperature: Option<u32>,
+    load_length: Option<f64>,
+    load_speed: Option<f64>,
+    unload_length: Option<f64>,
+    unload_speed: Option<f64>,
+    purge_length: Option<f64>,
+    diameter: Option<f64>,
+    density: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RawWebhookConfig {
+    name: String,
+    #[serde(default)]
+    method: Option<String>,
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// id of the printer that the unprefixed routes (`/job`, `/filament`, ...) act on
+    pub default_printer: String,
+    /// keyed by printer id, which is used in `/printers/{id}/...`
+    pub printers: BTreeMap<String, PrinterConfig>,
+    /// the doorbell is optional now that there are webhooks
+    pub homebridge: Option<HomebridgeConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+    /// notifiers without any route get every event
+    pub routes: Vec<RouteConfig>,
+    pub quiet_hours: Option<QuietHoursConfig>,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub confirm: ConfirmConfig,
+    pub heating: HeatingConfig,
+    /// the built in ones plus those from `[[filaments]]`
+    pub filaments: FilamentProfiles,
+    pub spools: SpoolsConfig,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrinterConfig {
+    pub backend: Backend,
+    /// OctoPrint api root, e.g. `http://192.168.1.113/api`,
+    /// or for Moonraker its root, e.g. `http://klipper.local`.
+    /// `None` only for Marlin, which has `serial` instead.
+    pub base_url: Option<Url>,
+    /// the printer's own key, which only this server knows and uses for every call.
+    /// Clients authenticate with tokens from `/tokens` instead.
+    /// Only OctoPrint needs one, so it can be empty for the others.
+    pub api_key: String,
+    /// digest auth login, only for PrusaLink
+    pub credentials: Option<Credentials>,
+    /// only for Marlin
+    pub serial: Option<SerialConfig>,
+    /// where the print head waits while filament is changed mid-print
+    pub park: ParkPosition,
+}
+
+/// Absolute X and Y in mm, somewhere the purged filament doesn't land on the print.
+/// The default is the corner at 0, 0.
+#[derive(Default, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ParkPosition {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialConfig {
+    /// e.g. `/dev/ttyUSB0`
+    pub port: String,
+    pub baud_rate: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// What the printer is controlled through
+#[derive(Default, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    OctoPrint,
+    /// Klipper
+    Moonraker,
+    /// Prusa MK4, XL, MINI, ...
+    PrusaLink,
+    /// G-code straight over USB
+    Marlin,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageConfig {
+    /// SQLite file for print history and anything else that should survive a restart
+    pub database: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthConfig {
+    /// always has every scope, used to create the client tokens.
+    /// Without it tokens can't be created.
+    pub admin_token: Option<String>,
+}
+
+/// Which actions need to be confirmed with a second call
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfirmConfig {
+    pub cancel: ConfirmPolicy,
+    pub restart: ConfirmPolicy,
+    /// how long the token from the first call can be used
+    pub timeout: Duration,
+}
+
+/// When heating up the hot end for a filament change counts as failed.
+/// Either way the heater is turned off again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeatingConfig {
+    /// for reaching the target at all
+    pub timeout: Duration,
+    /// the hot end has to get `min_rise` degrees warmer within this time,
+    /// otherwise the heater or thermistor is probably broken
+    pub stall_time: Duration,
+    pub min_rise: f64,
+}
+
+impl Default for HeatingConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10 * 60),
+            stall_time: Duration::from_secs(90),
+            min_rise: 3.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpoolsConfig {
+    /// a warning is sent once a spool has less than this left, in grams
+    pub low_grams: f64,
+}
+
+impl Default for SpoolsConfig {
+    fn default() -> Self {
+        Self { low_grams: 100. }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomebridgeConfig {
+    pub url: Url,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// used in log messages and to refer to this webhook from elsewhere in the config
+    pub name: String,
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    /// JSON (or a plain string) with `{{placeholder}}`s, see `NotifyWebhook`.
+    /// Without a body, the event is sent as JSON.
+    pub body: Option<serde_json::Value>,
+}
+
+/// Name under which the Homebridge doorbell can be used in routes
+pub const HOMEBRIDGE_NOTIFIER: &str = "homebridge";
+
+/// Sends events matching all of the given filters to a notifier.
+/// Filters that are left out match everything.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// `homebridge` or the name of a webhook
+    pub notifier: String,
+    pub events: Option<Vec<EventKind>>,
+    pub printers: Option<Vec<String>>,
+    /// only send during this time of day, e.g. `08:00-22:00`
+    pub hours: Option<TimeWindow>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    /// e.g. `22:00-07:00`
+    pub window: TimeWindow,
+    #[serde(default)]
+    pub mode: QuietMode,
+}
+
+/// What happens to non-critical events during quiet hours.
+/// Critical events (failed prints, lost printers) are always sent right away.
+#[derive(Default, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum QuietMode {
+    #[default]
+    Suppress,
+    /// send them once quiet hours are over
+    Delay,
+}
+
+/// Whether an action has to be confirmed before it happens
+#[derive(Default, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConfirmPolicy {
+    /// as before, the first call does it
+    #[default]
+    Never,
+    /// the first call only returns a token and a question for Siri to ask
+    Always,
+}
+
+impl Config {
+    /// Loads the config file pointed to by `PRINTER_ACTIONS_CONFIG` (or `config.toml`)
+    /// and applies env overrides. Call after `dotenv()` so `.env` values are visible.
+    /// A missing config file is fine as long as the env provides everything.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = std::env::var("PRINTER_ACTIONS_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("Config file {} not found, using env only", path.display());
+                None
+            }
+            Err(source) => return Err(ConfigError::Io { path, source }),
+        };
+
+        Self::from_sources(&path, contents.as_deref(), |key| std::env::var(key).ok())
+    }
+
+    /// `env` is a lookup function so that tests don't need to touch the process env.
+    /// `OCTOPRINT_URL` and `API_KEY` (or the deprecated `API_READ_KEY`) apply to the default printer,
+    /// which is called `default` if the file doesn't define any printers.
+    pub fn from_sources(
+        path: &Path,
+        contents: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut raw: RawConfig = match contents {
+            Some(contents) => toml::from_str(contents).map_err(|source| ConfigError::Parse {
+                path: path.to_path_buf(),
+                source,
+            })?,
+            None => RawConfig::default(),
+        };
+
+        if let Some(bind_address) = env("BIND_ADDRESS") {
+            raw.server.bind_address = Some(bind_address);
+        }
+        if let Some(port) = env("PORT") {
+            raw.server.port = Some(port.parse().map_err(|e| {
+                ConfigError::invalid("server.port", format!("{:?} from PORT: {}", port, e))
+            })?);
+        }
+        if let Some(default_printer) = env("DEFAULT_PRINTER") {
+            raw.default_printer = Some(default_printer);
+        }
+        if let Some(url) = env("HOMEBRIDGE_URL") {
+            raw.homebridge.url = Some(url);
+        }
+        if let Some(database) = env("DATABASE_PATH") {
+            raw.storage.database = Some(PathBuf::from(database));
+        }
+        if let Some(admin_token) = env("ADMIN_TOKEN") {
+            raw.auth.admin_token = Some(admin_token);
+        }
+
+        let base_url = env("OCTOPRINT_URL");
+        let api_key = env("API_KEY").or_else(|| {
+            let api_key = env("API_READ_KEY");
+            if api_key.is_some() {
+                log::warn!("API_READ_KEY is deprecated, please rename it to API_KEY");
+            }
+            api_key
+        });
+        if base_url.is_some() || api_key.is_some() {
+            let id = raw.default_printer_id()?;
+            let printer = raw.printers.entry(id).or_default();
+            if base_url.is_some() {
+                printer.base_url = base_url;
+            }
+            if api_key.is_some() {
+                printer.api_key = api_key;
+            }
+        }
+
+        raw.validate()
+    }
+}
+
+impl RawConfig {
+    /// Falls back to the only printer if there is exactly one
+    fn default_printer_id(&self) -> Result<String, ConfigError> {
+        if let Some(id) = &self.default_printer {
+            return Ok(id.clone());
+        }
+
+        let mut ids = self.printers.keys();
+        match (ids.next(), ids.next()) {
+            (None, _) => Ok("default".to_string()),
+            (Some(id), None) => Ok(id.clone()),
+            (Some(_), Some(_)) => Err(ConfigError::Missing {
+                key: "default_printer".to_string(),
+            }),
+        }
+    }
+
+    fn validate(self) -> Result<Config, ConfigError> {
+        let bind_address = self
+            .server
+            .bind_address
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0".to_string());
+        if bind_address.is_empty() {
+            return Err(ConfigError::invalid(
+                "server.bind_address",
+                "must not be empty",
+            ));
+        }
+
+        if self.printers.is_empty() {
+            return Err(ConfigError::Missing {
+                key: "printers".to_string(),
+            });
+        }
+
+        if let Some(admin_token) = &self.auth.admin_token {
+            // it can do everything, so it shouldn't be guessable
+            if admin_token.len() < 16 {
+                return Err(ConfigError::invalid(
+                    "auth.admin_token",
+                    "must be at least 16 characters long",
+                ));
+            }
+        }
+
+        let confirm_timeout = match self.confirm.timeout_seconds {
+            Some(0) => {
+                return Err(ConfigError::invalid(
+                    "confirm.timeout_seconds",
+                    "must be more than 0",
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => DEFAULT_CONFIRM_TIMEOUT,
+        };
+
+        let heating = self.heating.validate()?;
+
+        let low_grams = self
+            .spools
+            .low_grams
+            .unwrap_or(SpoolsConfig::default().low_grams);
+        if low_grams < 0. || low_grams.is_nan() {
+            return Err(ConfigError::invalid(
+                "spools.low_grams",
+                "must not be negative",
+            ));
+        }
+
+        let default_printer = self.default_printer_id()?;
+        if !self.printers.contains_key(&default_printer) {
+            return Err(ConfigError::invalid(
+                "default_printer",
+                format!("no printer named {:?}", default_printer),
+            ));
+        }
+
+        let printers = self
+            .printers
+            .into_iter()
+            .map(|(id, printer)| {
+                let printer = printer.validate(&id)?;
+                Ok((id, printer))
+            })
+            .collect::<Result<_, ConfigError>>()?;
+
+        Ok(Config {
+            server: ServerConfig {
+                bind_address,
+                port: self.server.port.unwrap_or(5001),
+            },
+            default_printer,
+            printers,
+            homebridge: self
+                .homebridge
+                .url
+                .map(|url| {
+                    Ok(HomebridgeConfig {
+                        url: parse_url(url, "homebridge.url")?,
+                    })
+                })
+                .transpose()?,
+            webhooks: validate_webhooks(self.webhooks)?,
+            routes: self.routes,
+            quiet_hours: self.quiet_hours,
+            storage: StorageConfig {
+                database: self
+                    .storage
+                    .database
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE_PATH)),
+            },
+            auth: AuthConfig {
+                admin_token: self.auth.admin_token,
+            },
+            confirm: ConfirmConfig {
+                cancel: self.confirm.cancel,
+                restart: self.confirm.restart,
+                timeout: confirm_timeout,
+            },
+            heating,
+            filaments: validate_filaments(self.filaments)?,
+            spools: SpoolsConfig { low_grams },
+        })
+        .and_then(validate_routes)
+    }
+}
+
+fn validate_routes(config: Config) -> Result<Config, ConfigError> {
+    for route in &config.routes {
+        let known_notifier = (route.notifier == HOMEBRIDGE_NOTIFIER && config.homebridge.is_some())
+            || config.webhooks.iter().any(|w| w.name == route.notifier);
+        if !known_notifier {
+            return Err(ConfigError::invalid(
+                "routes.notifier",
+                format!("no notifier named {:?}", route.notifier),
+            ));
+        }
+
+        for printer in route.printers.iter().flatten() {
+            if !config.printers.contains_key(printer) {
+                return Err(ConfigError::invalid(
+                    "routes.printers",
+                    format!("no printer named {:?}", printer),
+                ));
+            }
+        }
+    }
+    Ok(config)
+}
+
+fn validate_filaments(filaments: Vec<RawFilamentConfig>) -> Result<FilamentProfiles, ConfigError> {
+    let profiles = filaments
+        .into_iter()
+        .map(|filament| {
+            let key = |field: &str| format!("filaments.{}.{}", filament.name, field);
+
+            if filament.name.trim().is_empty() {
+                return Err(ConfigError::invalid("filaments.name", "must not be empty"));
+            }
+            let nozzle_temperature = HotEndTemperature::new(filament.nozzle_temperature)
+                .ok_or_else(|| {
+                    ConfigError::invalid(
+                        key("nozzle_temperature"),
+                        format!("must be between 1 and {}", HotEndTemperature::MAX),
+                    )
+                })?;
+
+            let default = FilamentProfile::new(filament.name.clone(), nozzle_temperature);
+            let length = |value: Option<f64>, field: &str, default: f64| match value {
+                Some(value) if value < 0. || value.is_nan() => {
+                    Err(ConfigError::invalid(key(field), "must not be negative"))
+                }
+                Some(value) => Ok(value),
+                None => Ok(default),
+            };
+            let positive = |value: Option<f64>, field: &str, default: f64| match value {
+                Some(value) if value <= 0. || value.is_nan() => {
+                    Err(ConfigError::invalid(key(field), "must be more than 0"))
+                }
+                Some(value) => Ok(value),
+                None => Ok(default),
+            };
+
+            Ok(FilamentProfile {
+                load_length: length(filament.load_length, "load_length", default.load_length)?,
+                load_speed: positive(filament.load_speed, "load_speed", default.load_speed)?,
+                unload_length: length(
+                    filament.unload_length,
+                    "unload_length",
+                    default.unload_length,
+                )?,
+                unload_speed: positive(
+                    filament.unload_speed,
+                    "unload_speed",
+                    default.unload_speed,
+                )?,
+                purge_length: length(filament.purge_length, "purge_length", default.purge_length)?,
+                diameter: positive(filament.diameter, "diameter", default.diameter)?,
+                density: positive(filament.density, "density", default.density)?,
+                bed_temperature: filament.bed_temperature,
+                aliases: filament.aliases,
+                ..default
+            })
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+
+    let profiles = FilamentProfiles::with(profiles);
+    // every name has to lead to exactly one profile
+    let mut names = std::collections::BTreeSet::new();
+    for name in profiles.iter().flat_map(|profile| profile.names()) {
+        if !names.insert(name.trim().to_lowercase()) {
+            return Err(ConfigError::invalid(
+                "filaments",
+                format!("{:?} is used by more than one filament", name),
+            ));
+        }
+    }
+    Ok(profiles)
+}
+
+fn validate_webhooks(webhooks: Vec<RawWebhookConfig>) -> Result<Vec<WebhookConfig>, ConfigError> {
+    let mut names = std::collections::BTreeSet::new();
+
+    webhooks
+        .into_iter()
+        .map(|webhook| {
+            let key = |field: &str| format!("webhooks.{}.{}", webhook.name, field);
+
+            if webhook.name.is_empty() || !names.insert(webhook.name.clone()) {
+                return Err(ConfigError::invalid(
+                    "webhooks.name",
+                    format!("{:?} is empty or used more than once", webhook.name),
+                ));
+            }
+
+            let method = match &webhook.method {
+                Some(method) => Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|e| ConfigError::invalid(key("method"), e.to_string()))?,
+                None => Method::POST,
+            };
+
+            let mut headers = HeaderMap::new();
+            for (name, value) in &webhook.headers {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| ConfigError::invalid(key("headers"), e.to_string()))?;
+                let value = HeaderValue::from_str(value)
+                    .map_err(|e| ConfigError::invalid(key("headers"), e.to_string()))?;
+                headers.append(name, value);
+            }
+
+            Ok(WebhookConfig {
+                method,
+                url: parse_url(webhook.url.clone(), &key("url"))?,
+                headers,
+                body: webhook.body,
+                name: webhook.name,
+            })
+        })
+        .collect()
+}
+
+impl RawHeatingConfig {
+    fn validate(&self) -> Result<HeatingConfig, ConfigError> {
+        let default = HeatingConfig::default();
+        let seconds = |value: Option<u64>, key: &str, default: Duration| match value {
+            Some(0) => Err(ConfigError::invalid(key, "must be more than 0")),
+            Some(seconds) => Ok(Duration::from_secs(seconds)),
+            None => Ok(default),
+        };
+
+        let min_rise = self.min_rise.unwrap_or(default.min_rise);
+        if min_rise <= 0. || min_rise.is_nan() {
+            return Err(ConfigError::invalid(
+                "heating.min_rise",
+                "must be more than 0",
+            ));
+        }
+
+        Ok(HeatingConfig {
+            timeout: seconds(
+                self.timeout_seconds,
+                "heating.timeout_seconds",
+                default.timeout,
+            )?,
+            stall_time: seconds(
+                self.stall_seconds,
+                "heating.stall_seconds",
+                default.stall_time,
+            )?,
+            min_rise,
+        })
+    }
+}
+
+impl RawPrinterConfig {
+    fn validate(self, id: &str) -> Result<PrinterConfig, ConfigError> {
+        let key = |field: &str| format!("printers.{}.{}", id, field);
+
+        // the id ends up in urls, so keep it simple
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ConfigError::invalid(
+                format!("printers.{}", id),
+                "printer ids may only contain letters, digits, '-' and '_'",
+            ));
+        }
+
+        let api_key = match self.backend {
+            Backend::OctoPrint => required(self.api_key, &key("api_key"))?,
+            _ => self.api_key.unwrap_or_default(),
+        };
+        if self.backend == Backend::OctoPrint && api_key.is_empty() {
+            return Err(ConfigError::invalid(key("api_key"), "must not be empty"));
+        }
+
+        let credentials = match self.backend {
+            Backend::PrusaLink => Some(Credentials {
+                username: self.username.unwrap_or_else(|| "maker".to_string()),
+                password: required(self.password, &key("password"))?,
+            }),
+            _ => {
+                if self.username.is_some() || self.password.is_some() {
+                    return Err(ConfigError::invalid(
+                        key("password"),
+                        "username and password are only used by the prusalink backend",
+                    ));
+                }
+                None
+            }
+        };
+
+        let (base_url, serial) = match self.backend {
+            Backend::Marlin => {
+                if self.base_url.is_some() {
+                    return Err(ConfigError::invalid(
+                        key("base_url"),
+                        "marlin printers are reached through serial_port",
+                    ));
+                }
+                let serial = SerialConfig {
+                    port: required(self.serial_port, &key("serial_port"))?,
+                    baud_rate: self.baud_rate.unwrap_or(DEFAULT_BAUD_RATE),
+                };
+                (None, Some(serial))
+            }
+            _ => {
+                if self.serial_port.is_some() || self.baud_rate.is_some() {
+                    return Err(ConfigError::invalid(
+                        key("serial_port"),
+                        "serial_port and baud_rate are only used by the marlin backend",
+                    ));
+                }
+                let base_url =
+                    parse_url(required(self.base_url, &key("base_url"))?, &key("base_url"))?;
+                (Some(base_url), None)
+            }
+        };
+
+        let park = self.park.unwrap_or_default();
+        if !park.x.is_finite() || !park.y.is_finite() {
+            return Err(ConfigError::invalid(key("park"), "must be a number of mm"));
+        }
+
+        Ok(PrinterConfig {
+            backend: self.backend,
+            base_url,
+            api_key,
+            credentials,
+            serial,
+            park,
+        })
+    }
+}
+
+fn required<T>(value: Option<T>, key: &str) -> Result<T, ConfigError> {
+    value.ok_or_else(|| ConfigError::Missing {
+        key: key.to_string(),
+    })
+}
+
+fn parse_url(url: String, key: &str) -> Result<Url, ConfigError> {
+    let parsed = Url::parse(&url)
+        .map_err(|e| ConfigError::invalid(key, format!("{:?} is not a valid URL: {}", url, e)))?;
+
+    match parsed.scheme() {
+        "http" | "https" => Ok(parsed),
+        scheme => Err(ConfigError::invalid(
+            key,
+            format!("{:?} has unsupported scheme {:?}", url, scheme),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: &str = r#"
+        default_printer = "ender"
+
+        [server]
+        bind_address = "127.0.0.1"
+        port = 8080
+
+        [printers.ender]
+        base_url = "http://192.168.1.113/api"
+        read_key = "abc"
+
+        [printers.prusa]
+        base_url = "http://192.168.1.114/api"
+        api_key = "def"
+
+        [printers.voron]
+        backend = "moonraker"
+        base_url = "http://voron.local"
+        park = { x = 340, y = 350 }
+
+        [printers.xl]
+        backend = "prusalink"
+        base_url = "http://xl.local"
+        password = "hunter2"
+
+        [printers.i3]
+        backend = "marlin"
+        serial_port = "/dev/ttyUSB0"
+
+        [homebridge]
+        url = "http://192.168.1.240:9091/printjob"
+
+        [confirm]
+        cancel = "always"
+        timeout_seconds = 30
+
+        [heating]
+        timeout_seconds = 900
+        min_rise = 2.5
+
+        [[filaments]]
+        name = "CF-PETG"
+        aliases = ["carbon PETG"]
+        nozzle_temperature = 250
+        bed_temperature = 80
+        load_speed = 60
+        purge_length = 30
+
+        [[filaments]]
+        name = "pla"
+        nozzle_temperature = 210
+
+        [spools]
+        low_grams = 50
+    "#;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_parse_full() {
+        let config = Config::from_sources(Path::new("test.toml"), Some(FULL), no_env).unwrap();
+        assert_eq!(config.server.bind_address, "127.0.0.1");
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.default_printer, "ender");
+        assert_eq!(config.printers.len(), 5);
+        assert_eq!(config.printers["ender"].backend, Backend::OctoPrint);
+        assert_eq!(config.printers["voron"].backend, Backend::Moonraker);
+        assert_eq!(config.printers["voron"].api_key, "");
+        assert_eq!(config.printers["voron"].credentials, None);
+        assert_eq!(
+            config.printers["voron"].park,
+            ParkPosition { x: 340., y: 350. }
+        );
+        assert_eq!(config.printers["ender"].park, ParkPosition::default());
+        assert_eq!(config.printers["i3"].base_url, None);
+        assert_eq!(
+            config.printers["i3"].serial,
+            Some(SerialConfig {
+                port: "/dev/ttyUSB0".to_string(),
+                baud_rate: DEFAULT_BAUD_RATE,
+            })
+        );
+        assert_eq!(
+            config.printers["xl"].credentials,
+            Some(Credentials {
+                username: "maker".to_string(),
+                password: "hunter2".to_string(),
+            })
+        );
+        assert_eq!(
+            config.printers["prusa"].base_url.as_ref().unwrap().as_str(),
+            "http://192.168.1.114/api"
+        );
+        // still read under its old name
+        assert_eq!(config.printers["ender"].api_key, "abc");
+        assert_eq!(config.printers["prusa"].api_key, "def");
+        assert_eq!(
+            config.confirm,
+            ConfirmConfig {
+                cancel: ConfirmPolicy::Always,
+                restart: ConfirmPolicy::Never,
+                timeout: Duration::from_secs(30),
+            }
+        );
+        let cf_petg = config.filaments.find("Carbon PETG").unwrap();
+        assert_eq!(cf_petg.name, "CF-PETG");
+        assert_eq!(cf_petg.load_speed, 60.);
+        assert_eq!(cf_petg.load_length, 500.);
+        assert_eq!(cf_petg.purge_length, 30.);
+        assert_eq!(
+            u32::from(config.filaments.find("PLA").unwrap().nozzle_temperature),
+            210
+        );
+        assert!(config.filaments.find("TPU").is_some());
+        assert_eq!(config.spools.low_grams, 50.);
+        assert_eq!(
+            config.heating,
+            HeatingConfig {
+                timeout: Duration::from_secs(900),
+                stall_time: HeatingConfig::default().stall_time,
+                min_rise: 2.5,
+            }
+        );
+        assert_eq!(
+            config.homebridge.unwrap().url.as_str(),
+            "http://192.168.1.240:9091/printjob"
+        );
+    }
+
+    #[test]
+    fn test_parse_webhooks() {
+        let with_webhook = format!(
+            "{}{}",
+            FULL,
+            r#"
+            [[webhooks]]
+            name = "ntfy"
+            url = "https://ntfy.sh/printer"
+            headers = { Title = "3D Printer" }
+            body = "{{message}}"
+
+            [[webhooks]]
+            name = "home-assistant"
+            method = "put"
+            url = "http://homeassistant.local:8123/api/webhook/printer"
+            body = { event = "{{event}}", seconds = "{{duration}}" }
+            "#
+        );
+        let config =
+            Config::from_sources(Path::new("test.toml"), Some(&with_webhook), no_env).unwrap();
+
+        assert_eq!(config.webhooks.len(), 2);
+        assert_eq!(config.webhooks[0].method, Method::POST);
+        assert_eq!(config.webhooks[0].headers["Title"], "3D Printer");
+        assert_eq!(config.webhooks[1].method, Method::PUT);
+        assert_eq!(
+            config.webhooks[1].body,
+            Some(serde_json::json!({"event": "{{event}}", "seconds": "{{duration}}"}))
+        );
+
+        let duplicate = format!(
+            "{}{}",
+            FULL,
+            r#"
+            [[webhooks]]
+            name = "a"
+            url = "https://ntfy.sh/printer"
+            [[webhooks]]
+            name = "a"
+            url = "https://ntfy.sh/printer"
+            "#
+        );
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&duplicate), no_env).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { .. }), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let with_routes = format!(
+            "{}{}",
+            FULL,
+            r#"
+            [[webhooks]]
+            name = "ntfy"
+            url = "https://ntfy.sh/printer"
+
+            [[routes]]
+            notifier = "ntfy"
+            events = ["printFinished", "printFailed"]
+            printers = ["prusa"]
+            hours = "08:00-22:00"
+
+            [quiet_hours]
+            window = "22:00-07:00"
+            mode = "delay"
+            "#
+        );
+        let config =
+            Config::from_sources(Path::new("test.toml"), Some(&with_routes), no_env).unwrap();
+        assert_eq!(
+            config.routes[0].events,
+            Some(vec![EventKind::PrintFinished, EventKind::PrintFailed])
+        );
+        assert_eq!(config.quiet_hours.unwrap().mode, QuietMode::Delay);
+
+        let unknown_notifier = format!("{}{}", FULL, "[[routes]]\nnotifier = \"slack\"");
+        let err = Config::from_sources(Path::new("test.toml"), Some(&unknown_notifier), no_env)
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { .. }), "{}", err);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let env = |key: &str| match key {
+            "PORT" => Some("6000".to_string()),
+            "OCTOPRINT_URL" => Some("http://octopi.local/api".to_string()),
+            "DATABASE_PATH" => Some("/var/lib/printer-actions.db".to_string()),
+            "ADMIN_TOKEN" => Some("0123456789abcdef0123".to_string()),
+            _ => None,
+        };
+        let config = Config::from_sources(Path::new("test.toml"), Some(FULL), env).unwrap();
+        assert_eq!(config.server.port, 6000);
+        assert_eq!(
+            config.auth.admin_token.as_deref(),
+            Some("0123456789abcdef0123")
+        );
+        assert_eq!(
+            config.storage.database,
+            PathBuf::from("/var/lib/printer-actions.db")
+        );
+        assert_eq!(
+            config.printers["ender"].base_url.as_ref().unwrap().as_str(),
+            "http://octopi.local/api"
+        );
+        assert_eq!(
+            config.printers["prusa"].base_url.as_ref().unwrap().as_str(),
+            "http://192.168.1.114/api"
+        );
+    }
+
+    #[test]
+    fn test_env_only_with_defaults() {
+        let env = |key: &str| match key {
+            "OCTOPRINT_URL" => Some("http://octopi.local/api".to_string()),
+            "API_KEY" => Some("abc".to_string()),
+            "HOMEBRIDGE_URL" => Some("http://homebridge.local:9091/printjob".to_string()),
+            _ => None,
+        };
+        let config = Config::from_sources(Path::new("test.toml"), None, env).unwrap();
+        assert_eq!(config.server.bind_address, "0.0.0.0");
+        assert_eq!(config.server.port, 5001);
+        assert_eq!(config.default_printer, "default");
+        assert_eq!(config.printers["default"].api_key, "abc");
+        assert_eq!(
+            config.storage.database,
+            PathBuf::from(DEFAULT_DATABASE_PATH)
+        );
+        assert_eq!(config.confirm.cancel, ConfirmPolicy::Never);
+        assert_eq!(config.confirm.timeout, DEFAULT_CONFIRM_TIMEOUT);
+
+        let old_env = |key: &str| match key {
+            "OCTOPRINT_URL" => Some("http://octopi.local/api".to_string()),
+            "API_READ_KEY" => Some("old".to_string()),
+            _ => None,
+        };
+        let config = Config::from_sources(Path::new("test.toml"), None, old_env).unwrap();
+        assert_eq!(config.printers["default"].api_key, "old");
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let err = Config::from_sources(Path::new("test.toml"), None, no_env).unwrap_err();
+        assert!(matches!(err, ConfigError::Missing { .. }), "{}", err);
+
+        let bad_url = FULL.replace("http://192.168.1.113/api", "192.168.1.113/api");
+        let err = Config::from_sources(Path::new("test.toml"), Some(&bad_url), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "printers.ender.base_url"),
+            "{}",
+            err
+        );
+
+        let no_default = FULL.replace(r#"default_printer = "ender""#, "");
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&no_default), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Missing { key } if key == "default_printer"),
+            "{}",
+            err
+        );
+
+        let unknown_default =
+            FULL.replace(r#"default_printer = "ender""#, r#"default_printer = "mk4""#);
+        let err = Config::from_sources(Path::new("test.toml"), Some(&unknown_default), no_env)
+            .unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "default_printer"),
+            "{}",
+            err
+        );
+
+        let no_password = FULL.replace(r#"password = "hunter2""#, "");
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&no_password), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Missing { key } if key == "printers.xl.password"),
+            "{}",
+            err
+        );
+
+        let marlin_url = FULL.replace(
+            r#"serial_port = "/dev/ttyUSB0""#,
+            r#"base_url = "http://i3.local""#,
+        );
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&marlin_url), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "printers.i3.base_url"),
+            "{}",
+            err
+        );
+
+        let octoprint_password = FULL.replace(
+            r#"read_key = "abc""#,
+            "read_key = \"abc\"\npassword = \"hunter2\"",
+        );
+        let err = Config::from_sources(Path::new("test.toml"), Some(&octoprint_password), no_env)
+            .unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "printers.ender.password"),
+            "{}",
+            err
+        );
+
+        let short_token = format!("{}{}", FULL, "[auth]\nadmin_token = \"abc\"");
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&short_token), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "auth.admin_token"),
+            "{}",
+            err
+        );
+
+        let no_timeout = FULL.replace("timeout_seconds = 30", "timeout_seconds = 0");
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&no_timeout), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "confirm.timeout_seconds"),
+            "{}",
+            err
+        );
+
+        let no_rise = FULL.replace("min_rise = 2.5", "min_rise = -1");
+        let err = Config::from_sources(Path::new("test.toml"), Some(&no_rise), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "heating.min_rise"),
+            "{}",
+            err
+        );
+
+        let too_hot = FULL.replace("nozzle_temperature = 250", "nozzle_temperature = 2500");
+        let err = Config::from_sources(Path::new("test.toml"), Some(&too_hot), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "filaments.CF-PETG.nozzle_temperature"),
+            "{}",
+            err
+        );
+
+        let taken_alias = FULL.replace(r#"aliases = ["carbon PETG"]"#, r#"aliases = ["petg"]"#);
+        let err =
+            Config::from_sources(Path::new("test.toml"), Some(&taken_alias), no_env).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::Invalid { key, .. } if key == "filaments"),
+            "{}",
+            err
+        );
+
+        let err = Config::from_sources(Path::new("test.toml"), Some("[server]\nprot = 1"), no_env)
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }), "{}", err);
+    }
+}
@@ -0,0 +1,184 @@
+//! Two-step confirmation for actions that a misheard Siri phrase shouldn't be able to trigger.
+//! The first call only gets a short-lived token and a question to read out,
+//! and the action happens when the same client sends that token back.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rand::RngCore;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::auth::ClientToken;
+use crate::config::{ConfirmConfig, ConfirmPolicy};
+use crate::utils::http_errors::AnyhowHTTPError;
+
+/// The actions that can be set to need a confirmation in `[confirm]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmAction {
+    Cancel,
+    Restart,
+}
+
+/// Returned by the first call, with 202
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingConfirmation {
+    /// send back as `?confirm=...` to go ahead
+    pub confirmation_token: String,
+    /// for Siri to ask, e.g. "Cancel benchy.gcode at 87%?"
+    pub message: String,
+    pub expires_in_seconds: u64,
+}
+
+struct Pending {
+    printer_id: String,
+    action: ConfirmAction,
+    client: ClientToken,
+    expires_at: Instant,
+}
+
+pub struct Confirmations {
+    config: ConfirmConfig,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl Confirmations {
+    pub fn new(config: ConfirmConfig) -> Self {
+        Self {
+            config,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn required(&self, action: ConfirmAction) -> bool {
+        let policy = match action {
+            ConfirmAction::Cancel => self.config.cancel,
+            ConfirmAction::Restart => self.config.restart,
+        };
+        policy == ConfirmPolicy::Always
+    }
+
+    /// Hands out a token that `client` can use once to do `action` on the printer
+    pub fn request(
+        &self,
+        printer_id: &str,
+        action: ConfirmAction,
+        client: &ClientToken,
+        message: String,
+    ) -> PendingConfirmation {
+        let mut bytes = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.expires_at > now);
+        pending.insert(
+            token.clone(),
+            Pending {
+                printer_id: printer_id.to_string(),
+                action,
+                client: client.clone(),
+                expires_at: now + self.config.timeout,
+            },
+        );
+
+        PendingConfirmation {
+            confirmation_token: token,
+            message,
+            expires_in_seconds: self.config.timeout.as_secs(),
+        }
+    }
+
+    /// Uses up the token if it was handed out to `client` for exactly this
+    pub fn confirm(
+        &self,
+        token: &str,
+        printer_id: &str,
+        action: ConfirmAction,
+        client: &ClientToken,
+    ) -> Result<(), AnyhowHTTPError> {
+        let mut pending = self.pending.lock().unwrap();
+        let matches = pending.get(token).is_some_and(|p| {
+            p.printer_id == printer_id
+                && p.action == action
+                && p.client == *client
+                && p.expires_at > Instant::now()
+        });
+        if !matches {
+            return Err(AnyhowHTTPError::Conflict409(
+                "That confirmation is unknown or has expired, please ask again".to_string(),
+            ));
+        }
+        pending.remove(token);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::auth::Scope;
+
+    fn client(name: &str) -> ClientToken {
+        ClientToken {
+            id: Some(1),
+            name: name.to_string(),
+            scopes: vec![Scope::JobControl],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_confirmations() {
+        let confirmations = Confirmations::new(ConfirmConfig {
+            cancel: ConfirmPolicy::Always,
+            restart: ConfirmPolicy::Never,
+            timeout: Duration::from_secs(30),
+        });
+        assert!(confirmations.required(ConfirmAction::Cancel));
+        assert!(!confirmations.required(ConfirmAction::Restart));
+
+        let phone = client("phone");
+        let pending = confirmations.request(
+            "ender",
+            ConfirmAction::Cancel,
+            &phone,
+            "Cancel benchy.gcode at 87%?".to_string(),
+        );
+        assert_eq!(pending.expires_in_seconds, 30);
+        let token = pending.confirmation_token;
+
+        let wrong = [
+            ("prusa", ConfirmAction::Cancel, &phone),
+            ("ender", ConfirmAction::Restart, &phone),
+            ("ender", ConfirmAction::Cancel, &client("watch")),
+        ];
+        for (printer_id, action, client) in wrong {
+            assert!(confirmations
+                .confirm(&token, printer_id, action, client)
+                .is_err());
+        }
+
+        assert!(confirmations
+            .confirm(&token, "ender", ConfirmAction::Cancel, &phone)
+            .is_ok());
+        assert!(
+            confirmations
+                .confirm(&token, "ender", ConfirmAction::Cancel, &phone)
+                .is_err(),
+            "only works once"
+        );
+
+        let token = confirmations
+            .request("ender", ConfirmAction::Cancel, &phone, String::new())
+            .confirmation_token;
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(matches!(
+            confirmations.confirm(&token, "ender", ConfirmAction::Cancel, &phone),
+            Err(AnyhowHTTPError::Conflict409(_))
+        ));
+    }
+}
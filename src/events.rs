@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data_defs::printer_job_state::{JobState, Tool0};
+use crate::utils::time_utils::Time;
+
+/// What we know about the print job an event is about, taken from `JobState`
+#[derive(Default, Debug, Clone, PartialEq, Serialize)]
+pub struct PrintInfo {
+    pub file_name: Option<String>,
+    /// `local` or `sdcard`
+    pub origin: Option<String>,
+    /// who started the print
+    pub user: Option<String>,
+    /// in seconds
+    pub duration: Option<i64>,
+    /// in percent
+    pub completion: Option<f64>,
+    /// filament needed by tool0 for the whole print, as estimated by the slicer
+    pub filament: Option<Tool0>,
+}
+
+impl From<&JobState> for PrintInfo {
+    fn from(job_state: &JobState) -> Self {
+        Self {
+            file_name: job_state.job.file.name.clone(),
+            origin: job_state.job.file.origin.clone(),
+            user: job_state.job.user.clone(),
+            duration: job_state.progress.print_time,
+            completion: job_state.progress.completion,
+            filament: job_state
+                .job
+                .filament
+                .as_ref()
+                .and_then(|filament| filament.tool0.clone()),
+        }
+    }
+}
+
+impl PrintInfo {
+    pub fn duration_human_readable(&self) -> Option<String> {
+        self.duration
+            .and_then(Time::from_seconds)
+            .map(Time::to_human_readable_briefly)
+    }
+
+    /// "printing benchy.gcode" or just "printing" if the file is unknown
+    fn printing(&self) -> String {
+        match &self.file_name {
+            Some(file_name) => format!("printing {}", file_name),
+            None => "printing".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FilamentAction {
+    Feed,
+    Retract,
+    /// `filament` is the one that was loaded
+    Swap,
+    /// mid-print, `filament` is the one that was loaded
+    Change,
+}
+
+/// The type of a `PrinterEvent` without its data, e.g. for filtering
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EventKind {
+    PrintStarted,
+    PrintFinished,
+    PrintCancelled,
+    PrintFailed,
+    PrintPaused,
+    PrintResumed,
+    FilamentActionFinished,
+    LowFilament,
+    PrinterDisconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "event")]
+pub enum PrinterEvent {
+    PrintStarted(PrintInfo),
+    PrintFinished(PrintInfo),
+    PrintCancelled(PrintInfo),
+    PrintFailed {
+        print: PrintInfo,
+        error: Option<String>,
+    },
+    PrintPaused(PrintInfo),
+    PrintResumed(PrintInfo),
+    FilamentActionFinished {
+        action: FilamentAction,
+        /// the profile's name
+        filament: String,
+        /// `None` if the action succeeded
+        error: Option<String>,
+    },
+    /// sent once, when a print leaves the spool below `spools.low_grams`
+    LowFilament {
+        /// e.g. "red PLA"
+        spool: String,
+        remaining_grams: f64,
+    },
+    PrinterDisconnected {
+        error: String,
+    },
+}
+
+impl PrinterEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::PrintStarted(_) => EventKind::PrintStarted,
+            Self::PrintFinished(_) => EventKind::PrintFinished,
+            Self::PrintCancelled(_) => EventKind::PrintCancelled,
+            Self::PrintFailed { .. } => EventKind::PrintFailed,
+            Self::PrintPaused(_) => EventKind::PrintPaused,
+            Self::PrintResumed(_) => EventKind::PrintResumed,
+            Self::FilamentActionFinished { .. } => EventKind::FilamentActionFinished,
+            Self::LowFilament { .. } => EventKind::LowFilament,
+            Self::PrinterDisconnected { .. } => EventKind::PrinterDisconnected,
+        }
+    }
+
+    /// Critical events are sent even during quiet hours
+    pub fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            Self::PrintFailed { .. } | Self::PrinterDisconnected { .. }
+        )
+    }
+
+    pub fn print(&self) -> Option<&PrintInfo> {
+        match self {
+            Self::PrintStarted(print)
+            | Self::PrintFinished(print)
+            | Self::PrintCancelled(print)
+            | Self::PrintFailed { print, .. }
+            | Self::PrintPaused(print)
+            | Self::PrintResumed(print) => Some(print),
+            Self::FilamentActionFinished { .. }
+            | Self::LowFilament { .. }
+            | Self::PrinterDisconnected { .. } => None,
+        }
+    }
+
+    /// A sentence describing the event, suitable for a push notification or Siri
+    pub fn describe(&self) -> String {
+        match self {
+            Self::PrintStarted(print) => format!("Started {}", print.printing()),
+            Self::PrintFinished(print) => match print.duration_human_readable() {
+                Some(duration) => {
+                    format!("Finished {}. Printing took {}", print.printing(), duration)
+                }
+                None => format!("Finished {}", print.printing()),
+            },
+            Self::PrintCancelled(print) => format!("Cancelled {}", print.printing()),
+            Self::PrintFailed { print, error } => match error {
+                Some(error) => format!("Failed {}: {}", print.printing(), error),
+                None => format!("Failed {}", print.printing()),
+            },
+            Self::PrintPaused(print) => format!("Paused {}", print.printing()),
+            Self::PrintResumed(print) => format!("Resumed {}", print.printing()),
+            Self::FilamentActionFinished {
+                action,
+                filament,
+                error,
+            } => {
+                let action = match action {
+                    FilamentAction::Feed => "feeding",
+                    FilamentAction::Retract => "removing",
+                    FilamentAction::Swap => "swapping to",
+                    FilamentAction::Change => "changing to",
+                };
+                match error {
+                    None => format!("Finished {} {}", action, filament),
+                    Some(error) => format!("Failed {} {}: {}", action, filament, error),
+                }
+            }
+            Self::LowFilament {
+                spool,
+                remaining_grams,
+            } => format!(
+                "Only {} grams of {} left on the spool",
+                remaining_grams.round(),
+                spool
+            ),
+            Self::PrinterDisconnected { error } => {
+                format!("The printer is not reachable: {}", error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_defs::printer_job_state::{self, Job, Progress};
+
+    #[test]
+    fn test_print_info_from_job_state() {
+        let job_state = JobState {
+            job: Job {
+                file: printer_job_state::File {
+                    name: Some("benchy.gcode".to_string()),
+                    ..Default::default()
+                },
+                filament: Some(printer_job_state::Filament {
+                    tool0: Some(Tool0 {
+                        length: 1234.5,
+                        volume: 2.9,
+                    }),
+                }),
+                ..Default::default()
+            },
+            progress: Progress {
+                completion: Some(100.),
+                print_time: Some(2 * 3600 + 25 * 60),
+                ..Default::default()
+            },
+            state: "Operational".to_string(),
+            error: None,
+        };
+
+        let print = PrintInfo::from(&job_state);
+        assert_eq!(print.file_name.as_deref(), Some("benchy.gcode"));
+        assert_eq!(print.filament.as_ref().map(|f| f.length), Some(1234.5));
+
+        assert_eq!(
+            PrinterEvent::PrintFinished(print).describe(),
+            "Finished printing benchy.gcode. Printing took 2 hours and 20 minutes"
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let event = PrinterEvent::PrintFailed {
+            print: PrintInfo::default(),
+            error: Some("Thermal runaway".to_string()),
+        };
+        assert_eq!(event.kind(), EventKind::PrintFailed);
+        assert_eq!(event.describe(), "Failed printing: Thermal runaway");
+
+        let event = PrinterEvent::FilamentActionFinished {
+            action: FilamentAction::Feed,
+            filament: "PETG".to_string(),
+            error: None,
+        };
+        assert_eq!(event.print(), None);
+        assert_eq!(event.describe(), "Finished feeding PETG");
+
+        let event = PrinterEvent::LowFilament {
+            spool: "red PLA".to_string(),
+            remaining_grams: 79.6,
+        };
+        assert_eq!(event.kind(), EventKind::LowFilament);
+        assert_eq!(
+            event.describe(),
+            "Only 80 grams of red PLA left on the spool"
+        );
+    }
+}
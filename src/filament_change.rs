@@ -0,0 +1,816 @@
+//! Loading and unloading filament, built from the basic `Printer` commands
+//! so that every backend gets it for free.
+
+use anyhow::{bail, ensure};
+use log::warn;
+use tokio::time::{Duration, Instant};
+
+use crate::config::{HeatingConfig, ParkPosition};
+use crate::data_defs::printer_job_action::PauseAction;
+use crate::data_defs::printer_move::PrinterMove;
+use crate::data_defs::printer_tool::{Targets, Tool};
+use crate::filaments::{FilamentProfile, HotEndTemperature};
+use crate::traits::printer_trait::Printer;
+use crate::utils::job_running::{JobProgress, Phase, Progress};
+
+/// How often the temperature is checked while heating
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where heating starts and ends in the job's percentage, the rest is moving and extruding
+const HEATING_PERCENT: (u8, u8) = (10, 90);
+
+/// How long a swap keeps the hot end hot while waiting for the new filament
+pub const SWAP_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// How far the print head is lifted off the print for a change mid-print before it moves to the side, in mm
+const PARK_LIFT: f64 = 10.;
+
+/// The printer finishes the moves it has buffered before it is paused
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn retract_filament<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    filament: &FilamentProfile,
+    heating: &HeatingConfig,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    heat_and_park(
+        printer,
+        api_key,
+        filament.nozzle_temperature,
+        heating,
+        progress,
+    )
+    .await?;
+
+    progress.phase(Phase::Unloading, HEATING_PERCENT.1);
+    unload(printer, api_key, filament).await?;
+    progress.phase(Phase::Finished, 100);
+    Ok(())
+}
+
+pub async fn feed_filament<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    filament: &FilamentProfile,
+    heating: &HeatingConfig,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    heat_and_park(
+        printer,
+        api_key,
+        filament.nozzle_temperature,
+        heating,
+        progress,
+    )
+    .await?;
+
+    progress.phase(Phase::Loading, HEATING_PERCENT.1);
+    load(printer, api_key, filament).await?;
+    progress.phase(Phase::Finished, 100);
+    Ok(())
+}
+
+/// Unloads `from` and loads `to` with one heat up, waiting in between
+/// until the new filament is inserted. Both are done at the hotter of the two temperatures.
+pub async fn swap_filament<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    from: &FilamentProfile,
+    to: &FilamentProfile,
+    heating: &HeatingConfig,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let temperature = from.nozzle_temperature.max(to.nozzle_temperature);
+    heat_and_park(printer, api_key, temperature, heating, progress).await?;
+
+    progress.phase(Phase::Unloading, HEATING_PERCENT.1);
+    unload(printer, api_key, from).await?;
+
+    let inserted = tokio::time::timeout(SWAP_WAIT, progress.wait_for_filament(93)).await;
+    if inserted.is_err() {
+        let error = format!(
+            "Nobody said continue within {} minutes, so the hot end was turned off",
+            SWAP_WAIT.as_secs() / 60
+        );
+        return fail_heating(printer, api_key, error).await;
+    }
+
+    progress.phase(Phase::Loading, 95);
+    load(printer, api_key, to).await?;
+    progress.phase(Phase::Finished, 100);
+    Ok(())
+}
+
+/// Changes filament in the middle of a print, like `M600`: pauses, parks the print head at `park`,
+/// unloads `from`, waits until the new filament is inserted, loads and purges `to`,
+/// then waits for the print's temperature, puts the print head back and resumes.
+/// If nobody says continue within `SWAP_WAIT` the hot end is turned off and the print stays paused.
+pub async fn change_filament_mid_print<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    from: &FilamentProfile,
+    to: &FilamentProfile,
+    heating: &HeatingConfig,
+    park: &ParkPosition,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let state = printer.printer_state(api_key).await?;
+    ensure!(
+        state.state.flags.printing,
+        "Nothing is printing, so there is no print to change the filament of"
+    );
+    let print_target = state.temperature.tool0.target;
+    let print_temperature = HotEndTemperature::new(print_target.round() as u32);
+
+    progress.phase(Phase::Pausing, 0);
+    printer.pause_job(api_key, PauseAction::Pause).await?;
+    wait_until_paused(printer, api_key).await?;
+
+    let changed: anyhow::Result<()> = async {
+        progress.phase(Phase::Parking, HEATING_PERCENT.0 / 2);
+        printer.save_position(api_key).await?;
+        progress.parked(true);
+        lift(printer, api_key, PARK_LIFT).await?;
+        move_to(printer, api_key, park).await?;
+
+        // the print's own temperature, unless one of the filaments needs it hotter
+        let temperature = from.nozzle_temperature.max(to.nozzle_temperature);
+        let temperature = print_temperature.map_or(temperature, |print| print.max(temperature));
+        set_hot_end(printer, api_key, temperature.into()).await?;
+        progress.phase(Phase::Heating, HEATING_PERCENT.0);
+        wait_for_temperature(printer, api_key, temperature, heating, progress).await?;
+
+        progress.phase(Phase::Unloading, HEATING_PERCENT.1);
+        unload(printer, api_key, from).await?;
+
+        let inserted = tokio::time::timeout(SWAP_WAIT, progress.wait_for_filament(93)).await;
+        if inserted.is_err() {
+            bail!(
+                "Nobody said continue within {} minutes",
+                SWAP_WAIT.as_secs() / 60
+            );
+        }
+
+        progress.phase(Phase::Loading, 95);
+        load(printer, api_key, to).await?;
+
+        // resuming at the wrong temperature would under- or over-extrude the next layers
+        set_hot_end(printer, api_key, print_target.round() as i64).await?;
+        if let Some(print_temperature) = print_temperature {
+            progress.phase(Phase::Heating, HEATING_PERCENT.1);
+            wait_for_temperature(printer, api_key, print_temperature, heating, progress).await?;
+        }
+
+        progress.phase(Phase::Resuming, 98);
+        printer.restore_position(api_key).await?;
+        progress.parked(false);
+        Ok(())
+    }
+    .await;
+    if let Err(e) = changed {
+        return fail_mid_print(printer, api_key, progress, e).await;
+    }
+
+    printer.pause_job(api_key, PauseAction::Resume).await?;
+    progress.phase(Phase::Finished, 100);
+    Ok(())
+}
+
+/// Leaves the print paused, with the print head back where it was and the hot end off,
+/// so that it can be resumed at the printer once somebody has had a look
+async fn fail_mid_print<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    progress: &Progress,
+    error: anyhow::Error,
+) -> anyhow::Result<()> {
+    if progress.current().parked {
+        match printer.restore_position(api_key).await {
+            Ok(()) => progress.parked(false),
+            Err(e) => warn!(
+                "Failed to move the print head back after the change failed: {}",
+                e
+            ),
+        }
+    }
+    let error = format!(
+        "{}. The hot end was turned off and the print is still paused",
+        error
+    );
+    fail_heating(printer, api_key, error).await
+}
+
+async fn wait_until_paused<P: Printer + ?Sized>(printer: &P, api_key: &str) -> anyhow::Result<()> {
+    let started_at = Instant::now();
+    while !printer.printer_state(api_key).await?.state.flags.paused {
+        ensure!(
+            started_at.elapsed() < PAUSE_TIMEOUT,
+            "The printer didn't pause within {} minutes",
+            PAUSE_TIMEOUT.as_secs() / 60
+        );
+        tokio::time::sleep(PAUSE_POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Moves the print head up by `z`
+async fn lift<P: Printer + ?Sized>(printer: &P, api_key: &str, z: f64) -> anyhow::Result<()> {
+    printer
+        .printhead_command(
+            api_key,
+            PrinterMove::Move {
+                x: None,
+                y: None,
+                z: Some(z),
+                absolute: Some(false),
+                speed: None,
+            },
+        )
+        .await
+}
+
+async fn move_to<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    park: &ParkPosition,
+) -> anyhow::Result<()> {
+    printer
+        .printhead_command(
+            api_key,
+            PrinterMove::Move {
+                x: Some(park.x),
+                y: Some(park.y),
+                z: None,
+                absolute: Some(true),
+                speed: None,
+            },
+        )
+        .await
+}
+
+async fn unload<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    filament: &FilamentProfile,
+) -> anyhow::Result<()> {
+    printer
+        .tool_command(
+            api_key,
+            Tool::Extrude {
+                amount: -filament.unload_length,
+                speed: Some(filament.unload_speed),
+            },
+        )
+        .await
+}
+
+/// Including the purge, if the profile has one
+async fn load<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    filament: &FilamentProfile,
+) -> anyhow::Result<()> {
+    printer
+        .tool_command(
+            api_key,
+            Tool::Extrude {
+                amount: filament.load_length,
+                speed: Some(filament.load_speed),
+            },
+        )
+        .await?;
+    if filament.purge_length > 0. {
+        printer
+            .tool_command(
+                api_key,
+                Tool::Extrude {
+                    amount: filament.purge_length,
+                    speed: Some(filament.load_speed),
+                },
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn cool_down<P: Printer + ?Sized>(printer: &P, api_key: &str) -> anyhow::Result<()> {
+    let state = printer.printer_state(api_key).await?;
+    // TODO: replace with something returning 409 (Conflict)
+    ensure!(state.state.flags.operational, "Printer not operational");
+
+    set_hot_end(printer, api_key, 0).await
+}
+
+/// Undoes what a cancelled job left behind, going by how far it got
+pub async fn clean_up<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    stopped: &JobProgress,
+) -> anyhow::Result<()> {
+    // the job may have been half way through a long extrusion.
+    // Not every backend can stop that, but cooling down has to happen either way
+    if let Err(e) = printer.quick_stop(api_key).await {
+        warn!("Failed to stop the extruder: {}", e);
+    }
+    // a change mid-print leaves the print head parked next to the paused print
+    if stopped.parked {
+        if let Err(e) = printer.restore_position(api_key).await {
+            warn!("Failed to move the print head back: {}", e);
+        }
+    }
+    printer.cool_down(api_key).await
+}
+
+/// Starts heating, moves the print head out of the way and waits for the temperature
+async fn heat_and_park<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    temperature: HotEndTemperature,
+    heating: &HeatingConfig,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let state = printer.printer_state(api_key).await?;
+    ensure!(state.state.flags.operational, "Printer not operational");
+
+    set_hot_end(printer, api_key, temperature.into()).await?;
+    progress.phase(Phase::Homing, 0);
+    printer
+        .printhead_command(api_key, PrinterMove::home_all())
+        .await?;
+    progress.phase(Phase::Parking, HEATING_PERCENT.0 / 2);
+    printer
+        .printhead_command(
+            api_key,
+            PrinterMove::Move {
+                x: None,
+                y: None,
+                z: Some(200.0),
+                absolute: Some(true),
+                speed: None,
+            },
+        )
+        .await?;
+
+    progress.phase(Phase::Heating, HEATING_PERCENT.0);
+    wait_for_temperature(printer, api_key, temperature, heating, progress).await
+}
+
+async fn set_hot_end<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    tool0: i64,
+) -> anyhow::Result<()> {
+    printer
+        .tool_command(
+            api_key,
+            Tool::Target {
+                targets: Targets { tool0 },
+            },
+        )
+        .await
+}
+
+/// This will block for a long time (10 min ish)
+/// waits until hot-end is within 5 degrees of target.
+/// Turns the heater off and fails if that takes longer than `heating.timeout`,
+/// or if the hot end doesn't get warmer as fast as `heating` expects.
+pub async fn wait_for_temperature<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    target: HotEndTemperature,
+    heating: &HeatingConfig,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let started_at = Instant::now();
+    let mut start = None;
+    // the last time the temperature rose by `min_rise`, and to what
+    let mut last_rise: Option<(Instant, f64)> = None;
+    loop {
+        let state = printer.printer_state(api_key).await?;
+        let actual = state.temperature.tool0.actual;
+        let start = *start.get_or_insert(actual);
+        progress.temperature(
+            actual,
+            target.into(),
+            heating_percent(start, actual, target),
+        );
+        if target.within_5_degrees_of(actual) {
+            break;
+        }
+
+        let now = Instant::now();
+        if now - started_at >= heating.timeout {
+            let error = format!(
+                "The hot end only got to {} of {} degrees in {} minutes",
+                actual.round(),
+                u32::from(target),
+                heating.timeout.as_secs() / 60
+            );
+            return fail_heating(printer, api_key, error).await;
+        }
+
+        // cooling down to a lower target is only covered by the timeout
+        let heating_up = actual < f64::from(target);
+        match last_rise {
+            Some((_, from)) if actual - from >= heating.min_rise => last_rise = Some((now, actual)),
+            Some((since, from)) if heating_up && now - since >= heating.stall_time => {
+                let error = format!(
+                    "The hot end isn't heating up, it went from {} to {} degrees in {} seconds. \
+                    Check the heater and the thermistor",
+                    from.round(),
+                    actual.round(),
+                    (now - since).as_secs()
+                );
+                return fail_heating(printer, api_key, error).await;
+            }
+            Some(_) => {}
+            None => last_rise = Some((now, actual)),
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Leaving a faulty heater on is the worst that could happen, so failing to turn it off is only logged
+async fn fail_heating<P: Printer + ?Sized>(
+    printer: &P,
+    api_key: &str,
+    error: String,
+) -> anyhow::Result<()> {
+    if let Err(e) = set_hot_end(printer, api_key, 0).await {
+        warn!("Failed to turn off the hot end after heating failed: {}", e);
+    }
+    bail!(error)
+}
+
+/// How far heating from `start` to `target` got, as part of `HEATING_PERCENT`
+fn heating_percent(start: f64, actual: f64, target: HotEndTemperature) -> u8 {
+    let (from, to) = HEATING_PERCENT;
+    let target = f64::from(target);
+    let done = if target > start {
+        ((actual - start) / (target - start)).clamp(0., 1.)
+    } else {
+        1.
+    };
+    from + (done * f64::from(to - from)).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filaments::FilamentProfiles;
+    use crate::simulated_printer::{SimStatus, SimulatedPrinter};
+    use crate::utils::job_running::{run_job, JobStatus, LongRunningJob};
+
+    const PARK: ParkPosition = ParkPosition { x: 0., y: 200. };
+    /// somewhere over the middle of the bed
+    const PRINTING_AT: [f64; 3] = [120., 110., 4.];
+
+    fn profile(name: &str) -> FilamentProfile {
+        FilamentProfiles::default().find(name).unwrap().clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_for_temperature() {
+        let printer = SimulatedPrinter::new(1.);
+        let target = HotEndTemperature::new(200).unwrap();
+        set_hot_end(&printer, "", 200).await.unwrap();
+
+        let start = Instant::now();
+        let progress = Progress::default();
+        let receiver = progress.subscribe();
+        wait_for_temperature(&printer, "", target, &HeatingConfig::default(), &progress)
+            .await
+            .unwrap();
+
+        // a few minutes of simulated heating, polled every 10 s
+        let waited = start.elapsed();
+        assert!(waited > Duration::from_secs(60), "{:?}", waited);
+        assert!(waited < Duration::from_secs(300), "{:?}", waited);
+        assert!(target.within_5_degrees_of(printer.snapshot().tool_actual));
+        let last = receiver.borrow().clone();
+        assert_eq!(last.target_temperature, Some(200.));
+        assert!(last.percent >= 85, "{:?}", last);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_feed_and_retract() {
+        let printer = SimulatedPrinter::new(1.);
+
+        let progress = Progress::default();
+        let receiver = progress.subscribe();
+        printer
+            .feed_filament("", &profile("PETG"), &HeatingConfig::default(), &progress)
+            .await
+            .unwrap();
+        assert_eq!(receiver.borrow().phase, Phase::Finished);
+        assert_eq!(receiver.borrow().percent, 100);
+        let state = printer.snapshot();
+        assert!(state.homed);
+        assert_eq!(state.tool_target, 230.);
+        assert_eq!(state.extruded, 500.);
+
+        printer
+            .retract_filament(
+                "",
+                &profile("PETG"),
+                &HeatingConfig::default(),
+                &Progress::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(printer.snapshot().extruded, 50.);
+
+        let purging = FilamentProfile {
+            purge_length: 30.,
+            ..profile("PLA")
+        };
+        printer
+            .feed_filament("", &purging, &HeatingConfig::default(), &progress)
+            .await
+            .unwrap();
+        assert_eq!(printer.snapshot().extruded, 580.);
+
+        printer.cool_down("").await.unwrap();
+        assert_eq!(printer.snapshot().tool_target, 0.);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_swap() {
+        let printer = std::sync::Arc::new(SimulatedPrinter::new(1.));
+        let mut job = LongRunningJob::default();
+        let swap = |job: &mut LongRunningJob| {
+            let printer = printer.clone();
+            run_job(
+                |progress| async move {
+                    let (from, to) = (profile("PLA"), profile("PETG"));
+                    swap_filament(
+                        &*printer,
+                        "",
+                        &from,
+                        &to,
+                        &HeatingConfig::default(),
+                        &progress,
+                    )
+                    .await
+                    .map(|_| "Swapped".to_string())
+                },
+                job,
+            )
+            .unwrap();
+        };
+
+        swap(&mut job);
+        while job.current_progress().unwrap().phase != Phase::WaitingForFilament {
+            assert!(!job.proceed());
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        let state = printer.snapshot();
+        assert_eq!(state.tool_target, 230., "the hotter of the two");
+        assert_eq!(state.extruded, -450.);
+
+        assert!(job.proceed());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(matches!(
+            job.take_status().await.unwrap(),
+            JobStatus::Finished(message) if message == "Swapped"
+        ));
+        assert_eq!(printer.snapshot().extruded, 50.);
+
+        // nobody comes back to insert the new filament
+        swap(&mut job);
+        tokio::time::sleep(SWAP_WAIT + Duration::from_secs(300)).await;
+        assert!(matches!(
+            job.take_status().await.unwrap(),
+            JobStatus::Error(error) if error.starts_with("Nobody said continue within 15 minutes")
+        ));
+        assert_eq!(printer.snapshot().tool_target, 0.);
+        assert_eq!(printer.snapshot().extruded, -400.);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_change_mid_print() {
+        let printer = std::sync::Arc::new(SimulatedPrinter::new(1.));
+        let mut job = LongRunningJob::default();
+        let change = |job: &mut LongRunningJob| {
+            let printer = printer.clone();
+            run_job(
+                |progress| async move {
+                    let (from, to) = (profile("PLA"), profile("PETG"));
+                    change_filament_mid_print(
+                        &*printer,
+                        "",
+                        &from,
+                        &to,
+                        &HeatingConfig::default(),
+                        &PARK,
+                        &progress,
+                    )
+                    .await
+                    .map(|_| "Changed".to_string())
+                },
+                job,
+            )
+            .unwrap();
+        };
+
+        change(&mut job);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(matches!(
+            job.take_status().await.unwrap(),
+            JobStatus::Error(error) if error.starts_with("Nothing is printing")
+        ));
+
+        printer.start_job("").await.unwrap();
+        // the start gcode heats to 200
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        printer.update(|state| state.head = PRINTING_AT);
+        change(&mut job);
+        while job.current_progress().unwrap().phase != Phase::WaitingForFilament {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        let state = printer.snapshot();
+        assert_eq!(state.status, SimStatus::Paused);
+        assert_eq!(state.tool_target, 230., "hot enough for PETG");
+        assert_eq!(state.extruded, -450.);
+        assert_eq!(state.head, [PARK.x, PARK.y, PRINTING_AT[2] + PARK_LIFT]);
+        let paused_at = state.print_time;
+
+        assert!(job.proceed());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // cooling back down from 230 before the print goes on
+        let state = printer.snapshot();
+        assert_eq!(job.current_progress().unwrap().phase, Phase::Heating);
+        assert_eq!(state.status, SimStatus::Paused);
+        assert_eq!(state.tool_target, 200.);
+        assert_eq!(state.head[..2], [PARK.x, PARK.y]);
+
+        while job.current_progress().is_some() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        assert!(matches!(
+            job.take_status().await.unwrap(),
+            JobStatus::Finished(message) if message == "Changed"
+        ));
+        let state = printer.snapshot();
+        assert_eq!(state.status, SimStatus::Printing);
+        assert!(HotEndTemperature::new(200)
+            .unwrap()
+            .within_5_degrees_of(state.tool_actual));
+        assert_eq!(state.extruded, 50.);
+        assert_eq!(state.head, PRINTING_AT);
+        assert!(state.print_time.unwrap() - paused_at.unwrap() <= POLL_INTERVAL.as_secs_f64());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_change_mid_print_failures() {
+        let printer = std::sync::Arc::new(SimulatedPrinter::new(1.));
+        let mut job = LongRunningJob::default();
+        let change = |job: &mut LongRunningJob| {
+            let printer = printer.clone();
+            run_job(
+                |progress| async move {
+                    let petg = profile("PETG");
+                    change_filament_mid_print(
+                        &*printer,
+                        "",
+                        &petg,
+                        &petg,
+                        &HeatingConfig::default(),
+                        &PARK,
+                        &progress,
+                    )
+                    .await
+                    .map(|_| "Changed".to_string())
+                },
+                job,
+            )
+            .unwrap();
+        };
+        printer.start_job("").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        printer.update(|state| state.head = PRINTING_AT);
+
+        printer.update(|state| state.heater_broken = true);
+        change(&mut job);
+        while job.current_progress().is_some() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        assert!(matches!(
+            job.take_status().await.unwrap(),
+            JobStatus::Error(error) if error.starts_with("The hot end isn't heating up")
+        ));
+        let state = printer.snapshot();
+        assert_eq!(state.status, SimStatus::Paused);
+        assert_eq!(state.head, PRINTING_AT, "back on the print");
+        assert_eq!(state.tool_target, 0.);
+
+        printer.update(|state| state.heater_broken = false);
+        printer.pause_job("", PauseAction::Resume).await.unwrap();
+        printer.update(|state| state.moves_refused = true);
+        change(&mut job);
+        while job.current_progress().is_some() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        assert!(matches!(
+            job.take_status().await.unwrap(),
+            JobStatus::Error(error) if error.starts_with("Printer refused to move")
+                && error.ends_with("the print is still paused")
+        ));
+        let state = printer.snapshot();
+        assert_eq!(state.status, SimStatus::Paused);
+        assert_eq!(state.tool_target, 0., "not left hot");
+
+        printer.update(|state| state.moves_refused = false);
+        printer.pause_job("", PauseAction::Resume).await.unwrap();
+        change(&mut job);
+        while job.current_progress().unwrap().phase != Phase::WaitingForFilament {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        let stopped = job.cancel().await.unwrap();
+        assert!(stopped.parked);
+        clean_up(&*printer, "", &stopped).await.unwrap();
+        let state = printer.snapshot();
+        assert_eq!(state.status, SimStatus::Paused);
+        assert_eq!(state.head, PRINTING_AT);
+        assert_eq!(state.tool_target, 0.);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refuses_while_printing() {
+        let printer = SimulatedPrinter::new(1.);
+        printer.start_job("").await.unwrap();
+        assert!(printer
+            .feed_filament(
+                "",
+                &profile("PLA"),
+                &HeatingConfig::default(),
+                &Progress::default(),
+            )
+            .await
+            .is_err());
+
+        printer.fail("Thermal Runaway");
+        let err = printer
+            .retract_filament(
+                "",
+                &profile("PLA"),
+                &HeatingConfig::default(),
+                &Progress::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Printer not operational");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heating_failures() {
+        let printer = SimulatedPrinter::new(1.);
+        printer.update(|state| state.heater_broken = true);
+        let target = HotEndTemperature::new(200).unwrap();
+        set_hot_end(&printer, "", 200).await.unwrap();
+
+        let start = Instant::now();
+        let heating = HeatingConfig::default();
+        let err = wait_for_temperature(&printer, "", target, &heating, &Progress::default())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("The hot end isn't heating up"),
+            "{}",
+            err
+        );
+        assert!(start.elapsed() < heating.stall_time + POLL_INTERVAL * 2);
+        assert_eq!(printer.snapshot().tool_target, 0., "heater turned off");
+
+        // heats up fine, just not within the timeout
+        printer.update(|state| state.heater_broken = false);
+        set_hot_end(&printer, "", 200).await.unwrap();
+        let impatient = HeatingConfig {
+            timeout: Duration::from_secs(30),
+            ..heating
+        };
+        let err = wait_for_temperature(&printer, "", target, &impatient, &Progress::default())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("The hot end only got to"),
+            "{}",
+            err
+        );
+        assert_eq!(printer.snapshot().tool_target, 0.);
+    }
+
+    #[test]
+    fn test_heating_percent() {
+        let target = HotEndTemperature::new(200).unwrap();
+        assert_eq!(heating_percent(20., 20., target), 10);
+        assert_eq!(heating_percent(20., 110., target), 50);
+        assert_eq!(heating_percent(20., 210., target), 90);
+        assert_eq!(heating_percent(205., 200., target), 90);
+    }
+}
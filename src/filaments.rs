@@ -1,33 +1,120 @@
-use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
-#[allow(clippy::upper_case_acronyms)]
-pub enum Filament {
-    PLA,
-    PETG,
-    TPU,
+use serde::Serialize;
+
+/// How to load and unload one kind of filament. The built in PLA, PETG and TPU
+/// can be changed and more added with `[[filaments]]` in the config.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilamentProfile {
+    pub name: String,
+    /// other names it can be asked for by, e.g. "carbon PETG" for "CF-PETG"
+    pub aliases: Vec<String>,
+    pub nozzle_temperature: HotEndTemperature,
+    /// what prints with it need, for Shortcuts to show. Loading doesn't heat the bed.
+    pub bed_temperature: Option<u32>,
+    /// in mm
+    pub load_length: f64,
+    /// in mm/min
+    pub load_speed: f64,
+    pub unload_length: f64,
+    pub unload_speed: f64,
+    /// extruded after loading, slowly, until the old colour is gone. In mm.
+    pub purge_length: f64,
+    /// in mm, for turning lengths into grams
+    pub diameter: f64,
+    /// in g/cm³
+    pub density: f64,
+}
+
+impl FilamentProfile {
+    /// With the lengths and speeds that were used before there were profiles
+    pub fn new(name: impl Into<String>, nozzle_temperature: HotEndTemperature) -> Self {
+        Self {
+            name: name.into(),
+            aliases: Vec::new(),
+            nozzle_temperature,
+            bed_temperature: None,
+            load_length: 500.,
+            load_speed: 80.,
+            unload_length: 450.,
+            unload_speed: 250.,
+            purge_length: 0.,
+            diameter: 1.75,
+            density: 1.24,
+        }
+    }
+
+    /// How much `length` mm of this filament weighs
+    pub fn grams(&self, length: f64) -> f64 {
+        let radius = self.diameter / 2.;
+        // mm³ to cm³
+        std::f64::consts::PI * radius * radius * length / 1000. * self.density
+    }
+
+    fn is_called(&self, name: &str) -> bool {
+        self.names().any(|n| n.eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
 }
 
-impl FromStr for Filament {
-    type Err = ();
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct FilamentProfiles(Vec<FilamentProfile>);
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "PLA" => Ok(Filament::PLA),
-            "PETG" => Ok(Filament::PETG),
-            "TPU" => Ok(Filament::TPU),
-            _ => Err(()),
+impl Default for FilamentProfiles {
+    fn default() -> Self {
+        let profile = |name: &str, nozzle: u32, bed: u32, density: f64| FilamentProfile {
+            bed_temperature: Some(bed),
+            density,
+            ..FilamentProfile::new(name, HotEndTemperature::new(nozzle).unwrap())
+        };
+        Self(vec![
+            profile("PLA", 200, 60, 1.24),
+            profile("PETG", 230, 80, 1.27),
+            profile("TPU", 220, 50, 1.21),
+        ])
+    }
+}
+
+impl FilamentProfiles {
+    /// The built in profiles, with `profiles` replacing those of the same name
+    pub fn with(profiles: Vec<FilamentProfile>) -> Self {
+        let mut all = Self::default();
+        for profile in profiles {
+            match all
+                .0
+                .iter_mut()
+                .find(|p| p.name.eq_ignore_ascii_case(&profile.name))
+            {
+                Some(existing) => *existing = profile,
+                None => all.0.push(profile),
+            }
         }
+        all
+    }
+
+    /// By name or alias, ignoring case
+    pub fn find(&self, name: &str) -> Option<&FilamentProfile> {
+        self.0.iter().find(|profile| profile.is_called(name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &FilamentProfile> {
+        self.0.iter()
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
 pub struct HotEndTemperature(u32);
 
 impl HotEndTemperature {
+    /// Nylon and ASA need up to 270, anything above that is most likely a typo
+    pub const MAX: u32 = 300;
+
     pub fn new(temp: u32) -> Option<Self> {
-        if temp > 0 && temp <= 250 {
+        if temp > 0 && temp <= Self::MAX {
             Some(Self(temp))
         } else {
             None
@@ -57,12 +144,30 @@ impl From<HotEndTemperature> for f64 {
     }
 }
 
-impl From<Filament> for HotEndTemperature {
-    fn from(filament: Filament) -> Self {
-        match filament {
-            Filament::PLA => Self::new(200).unwrap(),
-            Filament::PETG => Self::new(230).unwrap(),
-            Filament::TPU => Self::new(220).unwrap(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let asa = FilamentProfile {
+            aliases: vec!["acrylic".to_string()],
+            ..FilamentProfile::new("ASA", HotEndTemperature::new(250).unwrap())
+        };
+        let hotter_petg = FilamentProfile::new("petg", HotEndTemperature::new(240).unwrap());
+        let profiles = FilamentProfiles::with(vec![asa, hotter_petg]);
+
+        assert_eq!(profiles.iter().count(), 4);
+        assert_eq!(profiles.find("pla").unwrap().name, "PLA");
+        assert_eq!(profiles.find(" Acrylic ").unwrap().name, "ASA");
+        assert_eq!(
+            profiles.find("PETG").unwrap().nozzle_temperature,
+            HotEndTemperature::new(240).unwrap()
+        );
+        assert!(profiles.find("Nylon").is_none());
+        // a 1 kg spool of PLA is about 330 m
+        let pla = profiles.find("PLA").unwrap();
+        assert_eq!(pla.grams(330_000.).round(), 984.);
+        assert!(HotEndTemperature::new(301).is_none());
     }
 }
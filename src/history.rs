@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, Utc};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::events::{PrintInfo, PrinterEvent};
+use crate::storage::Database;
+
+/// How many weeks `/stats` reports filament usage for
+const WEEKS_IN_STATS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Outcome {
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Finished => "finished",
+            Self::Cancelled => "cancelled",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "finished" => Self::Finished,
+            "cancelled" => Self::Cancelled,
+            _ => Self::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrintRecord {
+    pub printer: String,
+    pub file_name: Option<String>,
+    pub origin: Option<String>,
+    pub user: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    /// in seconds
+    pub duration: Option<i64>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    /// in mm, only the part that was actually printed
+    pub filament_length: Option<f64>,
+    /// in cm³, only the part that was actually printed
+    pub filament_volume: Option<f64>,
+}
+
+impl PrintRecord {
+    /// Only events that end a print make a record
+    pub fn from_event(
+        printer_id: &str,
+        event: &PrinterEvent,
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+    ) -> Option<Self> {
+        let (print, outcome, error) = match event {
+            PrinterEvent::PrintFinished(print) => (print, Outcome::Finished, None),
+            PrinterEvent::PrintCancelled(print) => (print, Outcome::Cancelled, None),
+            PrinterEvent::PrintFailed { print, error } => (print, Outcome::Failed, error.clone()),
+            _ => return None,
+        };
+
+        let used = used_fraction(print, outcome);
+
+        Some(Self {
+            printer: printer_id.to_string(),
+            file_name: print.file_name.clone(),
+            origin: print.origin.clone(),
+            user: print.user.clone(),
+            started_at,
+            ended_at,
+            duration: print.duration,
+            outcome,
+            error,
+            filament_length: print.filament.as_ref().map(|f| f.length * used),
+            filament_volume: print.filament.as_ref().map(|f| f.volume * used),
+        })
+    }
+}
+
+/// The slicer estimate is for the whole print, so scale it by how far the print got
+fn used_fraction(print: &PrintInfo, outcome: Outcome) -> f64 {
+    match (outcome, print.completion) {
+        (Outcome::Finished, _) => 1.,
+        (_, Some(completion)) => (completion / 100.).clamp(0., 1.),
+        (_, None) => 0.,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WeeklyFilament {
+    /// the Monday the week starts on
+    pub week_start: NaiveDate,
+    /// in mm
+    pub length: f64,
+    /// in cm³
+    pub volume: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub total_prints: usize,
+    pub finished: usize,
+    pub cancelled: usize,
+    pub failed: usize,
+    pub total_hours: f64,
+    /// finished out of all prints, `None` if there are none
+    pub success_rate: Option<f64>,
+    /// most recent week first
+    pub filament_per_week: Vec<WeeklyFilament>,
+}
+
+impl Stats {
+    pub fn from_records(records: &[PrintRecord], today: NaiveDate) -> Self {
+        let count = |outcome| records.iter().filter(|r| r.outcome == outcome).count();
+        let finished = count(Outcome::Finished);
+
+        let total_seconds: i64 = records.iter().filter_map(|r| r.duration).sum();
+
+        let this_week = week_start(today);
+        let filament_per_week = (0..WEEKS_IN_STATS)
+            .map(|weeks_ago| {
+                let week_start = this_week - Duration::weeks(weeks_ago as i64);
+                let in_week = records.iter().filter(|r| {
+                    self::week_start(r.ended_at.with_timezone(&Local).date_naive()) == week_start
+                });
+                let (length, volume) = in_week.fold((0., 0.), |(length, volume), r| {
+                    (
+                        length + r.filament_length.unwrap_or(0.),
+                        volume + r.filament_volume.unwrap_or(0.),
+                    )
+                });
+                WeeklyFilament {
+                    week_start,
+                    length,
+                    volume,
+                }
+            })
+            .collect();
+
+        Self {
+            total_prints: records.len(),
+            finished,
+            cancelled: count(Outcome::Cancelled),
+            failed: count(Outcome::Failed),
+            total_hours: total_seconds as f64 / 3600.,
+            success_rate: (!records.is_empty()).then(|| finished as f64 / records.len() as f64),
+            filament_per_week,
+        }
+    }
+
+    /// For Siri
+    pub fn summary(&self) -> String {
+        let Some(success_rate) = self.success_rate else {
+            return "Nothing has been printed yet".to_string();
+        };
+
+        let this_week = self
+            .filament_per_week
+            .first()
+            .map_or(0., |week| week.length / 1000.);
+
+        format!(
+            "{} prints so far, {} of them successful, which is {}%. {} hours of printing in total. {:.1} meters of filament used this week",
+            self.total_prints,
+            self.finished,
+            (success_rate * 100.).round(),
+            self.total_hours.round(),
+            this_week,
+        )
+    }
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday().into())
+}
+
+pub struct PrintHistory {
+    db: Arc<Database>,
+}
+
+impl PrintHistory {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self { db }
+    }
+
+    pub fn record(&self, record: &PrintRecord) -> anyhow::Result<()> {
+        self.db.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO prints (printer, file_name, origin, user, started_at, ended_at,
+                    duration, outcome, error, filament_length, filament_volume)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    record.printer,
+                    record.file_name,
+                    record.origin,
+                    record.user,
+                    record.started_at,
+                    record.ended_at,
+                    record.duration,
+                    record.outcome.as_str(),
+                    record.error,
+                    record.filament_length,
+                    record.filament_volume,
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Most recent first. `limit` of `None` returns everything.
+    pub fn prints(
+        &self,
+        printer: Option<&str>,
+        limit: Option<u32>,
+    ) -> anyhow::Result<Vec<PrintRecord>> {
+        self.db.with_conn(|conn| {
+            let mut statement = conn.prepare(
+                "SELECT printer, file_name, origin, user, started_at, ended_at,
+                    duration, outcome, error, filament_length, filament_volume
+                FROM prints
+                WHERE ?1 IS NULL OR printer = ?1
+                ORDER BY ended_at DESC
+                LIMIT ?2",
+            )?;
+            let limit = limit.map_or(-1, i64::from);
+            let rows = statement.query_map(params![printer, limit], |row| {
+                Ok(PrintRecord {
+                    printer: row.get(0)?,
+                    file_name: row.get(1)?,
+                    origin: row.get(2)?,
+                    user: row.get(3)?,
+                    started_at: row.get(4)?,
+                    ended_at: row.get(5)?,
+                    duration: row.get(6)?,
+                    outcome: Outcome::parse(&row.get::<_, String>(7)?),
+                    error: row.get(8)?,
+                    filament_length: row.get(9)?,
+                    filament_volume: row.get(10)?,
+                })
+            })?;
+            rows.collect()
+        })
+    }
+
+    pub fn stats(&self, printer: Option<&str>) -> anyhow::Result<Stats> {
+        let records = self.prints(printer, None)?;
+        Ok(Stats::from_records(&records, Local::now().date_naive()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::data_defs::printer_job_state::Tool0;
+
+    fn print(completion: f64) -> PrintInfo {
+        PrintInfo {
+            file_name: Some("benchy.gcode".to_string()),
+            duration: Some(3600),
+            completion: Some(completion),
+            filament: Some(Tool0 {
+                length: 2000.,
+                volume: 5.,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn record(event: &PrinterEvent, ended_at: DateTime<Utc>) -> PrintRecord {
+        PrintRecord::from_event("ender", event, ended_at - Duration::hours(1), ended_at).unwrap()
+    }
+
+    #[test]
+    fn test_record_and_stats() {
+        let history = PrintHistory::new(Arc::new(Database::open_in_memory().unwrap()));
+        // a Wednesday
+        let now = Local
+            .with_ymd_and_hms(2026, 10, 14, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let finished = record(&PrinterEvent::PrintFinished(print(100.)), now);
+        let cancelled = record(
+            &PrinterEvent::PrintCancelled(print(25.)),
+            now - Duration::days(7),
+        );
+        let failed = PrintRecord::from_event(
+            "prusa",
+            &PrinterEvent::PrintFailed {
+                print: print(50.),
+                error: Some("Thermal runaway".to_string()),
+            },
+            now,
+            now,
+        )
+        .unwrap();
+        assert_eq!(cancelled.filament_length, Some(500.));
+        assert!(
+            PrintRecord::from_event("ender", &PrinterEvent::PrintStarted(print(0.)), now, now)
+                .is_none()
+        );
+
+        for r in [&cancelled, &finished, &failed] {
+            history.record(r).unwrap();
+        }
+
+        let all = history.prints(None, None).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2], cancelled);
+
+        let ender = history.prints(Some("ender"), Some(1)).unwrap();
+        assert_eq!(ender, vec![finished]);
+
+        let stats = Stats::from_records(&all, now.with_timezone(&Local).date_naive());
+        assert_eq!(stats.total_prints, 3);
+        assert_eq!(stats.finished, 1);
+        assert_eq!(stats.total_hours, 3.);
+        assert_eq!(stats.filament_per_week.len(), WEEKS_IN_STATS);
+        assert_eq!(
+            stats.filament_per_week[0].week_start,
+            NaiveDate::from_ymd_opt(2026, 10, 12).unwrap()
+        );
+        assert_eq!(stats.filament_per_week[0].length, 3000.);
+        assert_eq!(stats.filament_per_week[1].length, 500.);
+        assert_eq!(
+            stats.summary(),
+            "3 prints so far, 1 of them successful, which is 33%. 3 hours of printing in total. 3.0 meters of filament used this week"
+        );
+    }
+}
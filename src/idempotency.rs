@@ -0,0 +1,222 @@
+//! `Idempotency-Key` support, so that Shortcuts retrying a request on flaky Wi-Fi
+//! gets the original response instead of doing the action twice.
+
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::body::to_bytes;
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{FromRequest, HttpRequest, HttpResponse, ResponseError};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::auth::ClientToken;
+use crate::utils::http_errors::AnyhowHTTPError;
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+/// How long a response is kept for repeats of the same request
+pub const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// The `Idempotency-Key` header, along with the request it was sent with
+/// so that the same key can't replay the response of a different route.
+pub struct IdempotencyKey(Option<String>);
+
+impl FromRequest for IdempotencyKey {
+    type Error = AnyhowHTTPError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(idempotency_key(req))
+    }
+}
+
+fn idempotency_key(req: &HttpRequest) -> Result<IdempotencyKey, AnyhowHTTPError> {
+    let Some(key) = req.headers().get(IDEMPOTENCY_HEADER) else {
+        return Ok(IdempotencyKey(None));
+    };
+    let key = key.to_str().map_err(anyhow::Error::from)?;
+    Ok(IdempotencyKey(Some(format!(
+        "{} {} {}",
+        req.method(),
+        req.uri(),
+        key
+    ))))
+}
+
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    fn to_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(content_type) = &self.content_type {
+            response.insert_header((CONTENT_TYPE, content_type.clone()));
+        }
+        response.body(self.body.clone())
+    }
+}
+
+struct Entry {
+    /// empty while the first request is still running
+    response: Arc<OnceCell<CachedResponse>>,
+    created_at: Instant,
+}
+
+/// Responses by client token and idempotency key
+#[derive(Default)]
+pub struct IdempotencyCache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `handler` unless `client` already sent this key for this request within
+    /// `IDEMPOTENCY_WINDOW`, in which case it gets the same response again.
+    /// A repeat that arrives while the first is still running waits for it.
+    /// Server errors aren't kept, so those can be retried.
+    pub async fn run<F, Fut>(
+        &self,
+        key: &IdempotencyKey,
+        client: &ClientToken,
+        handler: F,
+    ) -> Result<HttpResponse, AnyhowHTTPError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<HttpResponse, AnyhowHTTPError>>,
+    {
+        let Some(key) = &key.0 else {
+            return handler().await;
+        };
+        let key = format!("{:?} {}", client.id, key);
+
+        let cell = {
+            let now = Instant::now();
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, entry| now < entry.created_at + IDEMPOTENCY_WINDOW);
+            entries
+                .entry(key)
+                .or_insert_with(|| Entry {
+                    response: Arc::new(OnceCell::new()),
+                    created_at: now,
+                })
+                .response
+                .clone()
+        };
+
+        let cached = cell
+            .get_or_try_init(|| async {
+                let response = handler().await.unwrap_or_else(|e| e.error_response());
+                if response.status().is_server_error() {
+                    return Err(response);
+                }
+
+                let status = response.status();
+                let content_type = response.headers().get(CONTENT_TYPE).cloned();
+                let body = to_bytes(response.into_body()).await.map_err(|e| {
+                    AnyhowHTTPError::InternalServerError500(e.to_string()).error_response()
+                })?;
+                Ok(CachedResponse {
+                    status,
+                    content_type,
+                    body,
+                })
+            })
+            .await;
+
+        Ok(match cached {
+            Ok(cached) => cached.to_response(),
+            Err(response) => response,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::auth::Scope;
+
+    async fn body(response: HttpResponse) -> Bytes {
+        to_bytes(response.into_body()).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idempotency() {
+        let cache = IdempotencyCache::new();
+        let calls = AtomicU32::new(0);
+        let handler = || async {
+            let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if n == 1 {
+                Ok(HttpResponse::Ok().body("Job started"))
+            } else {
+                Err(AnyhowHTTPError::Conflict409(
+                    "Already running a job".to_string(),
+                ))
+            }
+        };
+        let phone = ClientToken {
+            id: Some(1),
+            name: "phone".to_string(),
+            scopes: vec![Scope::Filament],
+        };
+        let key = IdempotencyKey(Some("POST /filament abc".to_string()));
+
+        let first = cache.run(&key, &phone, handler).await.unwrap();
+        let repeat = cache.run(&key, &phone, handler).await.unwrap();
+        assert_eq!(repeat.status(), StatusCode::OK);
+        assert_eq!(body(first).await, body(repeat).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // the original response is kept, errors included
+        let other = IdempotencyKey(Some("POST /filament def".to_string()));
+        let conflict = cache.run(&other, &phone, handler).await.unwrap();
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        let conflict = cache.run(&other, &phone, handler).await.unwrap();
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let watch = ClientToken {
+            id: Some(2),
+            ..phone.clone()
+        };
+        cache.run(&key, &watch, handler).await.unwrap();
+        // without a key it's just the handler
+        assert!(cache
+            .run(&IdempotencyKey(None), &phone, handler)
+            .await
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        tokio::time::advance(IDEMPOTENCY_WINDOW).await;
+        let expired = cache.run(&key, &phone, handler).await.unwrap();
+        assert_eq!(expired.status(), StatusCode::CONFLICT);
+
+        let failing = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(AnyhowHTTPError::InternalServerError500(
+                "Printer unreachable".to_string(),
+            ))
+        };
+        let key = IdempotencyKey(Some("DELETE /job abc".to_string()));
+        cache.run(&key, &phone, failing).await.unwrap();
+        cache.run(&key, &phone, failing).await.unwrap();
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            7,
+            "server errors can be retried"
+        );
+    }
+}
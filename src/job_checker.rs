@@ -1,41 +1,414 @@
 use std::sync::Arc;
 
-use log::info;
+use chrono::Utc;
+use log::{debug, info};
+use tokio::time::Duration;
 
+use crate::data_defs::printer_job_state::JobState;
+use crate::data_defs::printer_state::State;
+use crate::events::{PrintInfo, PrinterEvent};
+use crate::history::{PrintHistory, PrintRecord};
+use crate::spools::Spools;
 use crate::traits::{notify_trait::Notifier, printer_trait::Printer};
+use crate::utils::logging_util::LoggableResult;
+
+/// How often the printer is asked what it is doing
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many polls in a row may fail during a print before the printer is considered gone
+const MAX_FAILED_POLLS: u32 = 3;
 
 pub async fn job_checker(
+    printer_id: &str,
     printer_service: Arc<dyn Printer>,
-    notifier: impl Notifier,
-    api_read_key: &str,
+    notifier: &dyn Notifier,
+    history: &PrintHistory,
+    spools: &Spools,
+    api_key: &str,
 ) -> anyhow::Result<()> {
+    // a disconnect is only reported after the printer has been seen online,
+    // so starting the server while the printer is off stays quiet
+    let mut connected = false;
+    let mut first_poll = true;
+
     loop {
-        let status = printer_service.printer_state(api_read_key).await?;
+        match printer_service.printer_state(api_key).await {
+            Ok(status) => {
+                connected = true;
+
+                if status.state.flags.printing {
+                    let Ok(job_state) = printer_service.job_state(api_key).await.log_error() else {
+                        // the print is still there on the next poll
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                        continue;
+                    };
+                    info!("Print job started on {}", printer_id);
+                    let print = PrintInfo::from(&job_state);
+                    // the print may have been running for a while before we noticed
+                    let started_at =
+                        Utc::now() - chrono::Duration::seconds(print.duration.unwrap_or(0));
 
-        if status.state.flags.printing {
-            info!("Print job started");
-            wait_till_complete(printer_service.as_ref(), api_read_key).await?;
-            info!("Print job ended");
-            notifier.notify().await?;
+                    // if the server (re)started mid-print, the print didn't just start
+                    if !first_poll {
+                        notify(
+                            notifier,
+                            printer_id,
+                            PrinterEvent::PrintStarted(print.clone()),
+                        )
+                        .await;
+                    }
+
+                    let event = wait_till_complete(
+                        printer_id,
+                        printer_service.as_ref(),
+                        notifier,
+                        api_key,
+                        print,
+                    )
+                    .await?;
+                    info!("Print job ended on {}: {}", printer_id, event.describe());
+
+                    if let PrinterEvent::PrinterDisconnected { .. } = event {
+                        connected = false;
+                    }
+                    let record =
+                        PrintRecord::from_event(printer_id, &event, started_at, Utc::now());
+                    if let Some(record) = &record {
+                        history.record(record).log_error().ok();
+                    }
+                    notify(notifier, printer_id, event).await;
+
+                    let used = record.and_then(|record| record.filament_length);
+                    if let Some(length) = used {
+                        if let Ok(Some(warning)) =
+                            spools.use_filament(printer_id, length).log_error()
+                        {
+                            notify(notifier, printer_id, warning).await;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("Printer {} not reachable: {}", printer_id, e);
+                if connected {
+                    connected = false;
+                    notify(
+                        notifier,
+                        printer_id,
+                        PrinterEvent::PrinterDisconnected {
+                            error: e.to_string(),
+                        },
+                    )
+                    .await;
+                }
+            }
         }
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        first_poll = false;
+        tokio::time::sleep(POLL_INTERVAL).await;
     }
 }
 
+/// A failing notifier shouldn't stop us from watching the printer
+async fn notify(notifier: &dyn Notifier, printer_id: &str, event: PrinterEvent) {
+    notifier.notify(printer_id, &event).await.log_error().ok();
+}
+
+/// Waits until the print is no longer active (paused counts as active),
+/// reporting pauses along the way. Returns the event describing how it ended.
 async fn wait_till_complete(
+    printer_id: &str,
     printer_service: &dyn Printer,
-    api_read_key: &str,
-) -> anyhow::Result<()> {
+    notifier: &dyn Notifier,
+    api_key: &str,
+    mut print: PrintInfo,
+) -> anyhow::Result<PrinterEvent> {
+    let mut paused = false;
+    let mut failed_polls = 0;
+    // cancelling only lasts a moment, so remember if we ever saw it
+    let mut saw_cancelling = false;
+    let mut last_job_state: Option<JobState> = None;
+
     loop {
-        let status = printer_service.printer_state(api_read_key).await?;
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let status = match printer_service.printer_state(api_key).await {
+            Ok(status) => {
+                failed_polls = 0;
+                status
+            }
+            Err(e) => {
+                failed_polls += 1;
+                if failed_polls >= MAX_FAILED_POLLS {
+                    return Ok(PrinterEvent::PrinterDisconnected {
+                        error: e.to_string(),
+                    });
+                }
+                continue;
+            }
+        };
+        let flags = &status.state.flags;
+        saw_cancelling |= flags.cancelling;
 
-        if !status.state.flags.printing {
-            break;
+        // keep the latest info, as OctoPrint may forget some of it once the job is over
+        if let Ok(job_state) = printer_service.job_state(api_key).await {
+            print = merge_print_info(print, PrintInfo::from(&job_state));
+            last_job_state = Some(job_state);
         }
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        if flags.paused && !paused {
+            paused = true;
+            notify(
+                notifier,
+                printer_id,
+                PrinterEvent::PrintPaused(print.clone()),
+            )
+            .await;
+        } else if !flags.paused && paused && flags.printing {
+            paused = false;
+            notify(
+                notifier,
+                printer_id,
+                PrinterEvent::PrintResumed(print.clone()),
+            )
+            .await;
+        }
+
+        let active = flags.printing
+            || flags.paused
+            || flags.pausing
+            || flags.resuming
+            || flags.cancelling
+            || flags.finishing;
+        if !active {
+            let job_state = last_job_state.unwrap_or_default();
+            return Ok(
+                match classify_outcome(saw_cancelling, &status.state, &job_state) {
+                    PrintOutcome::Finished => PrinterEvent::PrintFinished(print),
+                    PrintOutcome::Cancelled => PrinterEvent::PrintCancelled(print),
+                    PrintOutcome::Failed(error) => PrinterEvent::PrintFailed { print, error },
+                },
+            );
+        }
     }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrintOutcome {
+    Finished,
+    Cancelled,
+    /// with the error reported by the printer, if any
+    Failed(Option<String>),
+}
+
+/// Works out how a print ended from the first printer and job state after it stopped being active.
+/// `saw_cancelling` is whether the cancelling flag was seen at any point during the print.
+pub fn classify_outcome(saw_cancelling: bool, state: &State, job_state: &JobState) -> PrintOutcome {
+    let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+
+    // OctoPrint reports e.g. "Error: ..." or "Offline after error" as the job state
+    let job_state_is_error = job_state.state.to_lowercase().contains("error");
+    if state.flags.error
+        || !state.error.is_empty()
+        || job_state.error.is_some()
+        || job_state_is_error
+    {
+        let error = non_empty(&state.error)
+            .or_else(|| job_state.error.clone())
+            .or_else(|| job_state_is_error.then(|| job_state.state.clone()));
+        return PrintOutcome::Failed(error);
+    }
+
+    if saw_cancelling || state.flags.cancelling || job_state.state == "Cancelling" {
+        return PrintOutcome::Cancelled;
+    }
+
+    match job_state.progress.completion {
+        // the cancelling flag may have come and gone between polls
+        Some(completion) if completion < 100. => PrintOutcome::Cancelled,
+        // no progress means OctoPrint already forgot the job, so assume all went well
+        _ => PrintOutcome::Finished,
+    }
+}
+
+/// prefers values from `newer`, but keeps what `older` knew if `newer` lost it
+fn merge_print_info(older: PrintInfo, newer: PrintInfo) -> PrintInfo {
+    PrintInfo {
+        file_name: newer.file_name.or(older.file_name),
+        origin: newer.origin.or(older.origin),
+        user: newer.user.or(older.user),
+        duration: newer.duration.or(older.duration),
+        completion: newer.completion.or(older.completion),
+        filament: newer.filament.or(older.filament),
+    }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config::SpoolsConfig;
+    use crate::data_defs::printer_job_action::PauseAction;
+    use crate::data_defs::printer_job_state::Progress;
+    use crate::data_defs::printer_state::Flags;
+    use crate::events::EventKind;
+    use crate::filaments::FilamentProfiles;
+    use crate::history::Outcome;
+    use crate::simulated_printer::SimulatedPrinter;
+    use crate::spools::NewSpool;
+    use crate::storage::Database;
+
+    fn operational() -> State {
+        State {
+            error: String::new(),
+            flags: Flags {
+                operational: true,
+                ready: true,
+                ..Default::default()
+            },
+            text: "Operational".to_string(),
+        }
+    }
+
+    fn job_at(completion: Option<f64>, state: &str) -> JobState {
+        JobState {
+            progress: Progress {
+                completion,
+                ..Default::default()
+            },
+            state: state.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_finished() {
+        let outcome = classify_outcome(false, &operational(), &job_at(Some(100.), "Operational"));
+        assert_eq!(outcome, PrintOutcome::Finished);
+
+        let outcome = classify_outcome(false, &operational(), &job_at(None, "Operational"));
+        assert_eq!(outcome, PrintOutcome::Finished);
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let outcome = classify_outcome(true, &operational(), &job_at(Some(100.), "Operational"));
+        assert_eq!(outcome, PrintOutcome::Cancelled);
+
+        let outcome = classify_outcome(false, &operational(), &job_at(Some(42.), "Operational"));
+        assert_eq!(outcome, PrintOutcome::Cancelled);
+    }
+
+    #[test]
+    fn test_failed() {
+        let mut state = operational();
+        state.flags.error = true;
+        state.flags.operational = false;
+        state.error = "Thermal Runaway".to_string();
+        let outcome = classify_outcome(false, &state, &job_at(Some(42.), "Error"));
+        assert_eq!(
+            outcome,
+            PrintOutcome::Failed(Some("Thermal Runaway".to_string()))
+        );
+
+        let outcome = classify_outcome(
+            true,
+            &operational(),
+            &job_at(Some(42.), "Offline after error"),
+        );
+        assert_eq!(
+            outcome,
+            PrintOutcome::Failed(Some("Offline after error".to_string()))
+        );
+    }
+
+    struct Recording(Mutex<Vec<EventKind>>);
+
+    #[async_trait::async_trait]
+    impl Notifier for Recording {
+        async fn notify(&self, _printer_id: &str, event: &PrinterEvent) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(event.kind());
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_job_checker() {
+        let printer = Arc::new(SimulatedPrinter::new(1.));
+        let notifier = Arc::new(Recording(Mutex::new(Vec::new())));
+        let database = Arc::new(Database::open_in_memory().unwrap());
+        let history = Arc::new(PrintHistory::new(database.clone()));
+        let spools = Arc::new(Spools::new(
+            database,
+            FilamentProfiles::default(),
+            SpoolsConfig::default(),
+        ));
+        let spool = NewSpool {
+            material: "PLA".to_string(),
+            colour: "red".to_string(),
+            initial_grams: 1000.,
+            remaining_grams: Some(105.),
+        };
+        let spool = spools.add(&spool).unwrap().unwrap();
+        spools.mount(spool.id, "sim").unwrap();
+
+        let checker = tokio::spawn({
+            let printer = printer.clone();
+            let notifier = notifier.clone();
+            let history = history.clone();
+            let spools = spools.clone();
+            async move {
+                job_checker(
+                    "sim",
+                    printer,
+                    notifier.as_ref(),
+                    history.as_ref(),
+                    spools.as_ref(),
+                    "",
+                )
+                .await
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        // a poll that fails just as the print starts doesn't lose the start
+        printer.update(|state| state.failing_job_polls = 1);
+        printer.start_job("").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1800)).await;
+        printer.pause_job("", PauseAction::Pause).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        printer.pause_job("", PauseAction::Resume).await.unwrap();
+        // the simulated benchy takes an hour
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+
+        assert_eq!(
+            *notifier.0.lock().unwrap(),
+            vec![
+                EventKind::PrintStarted,
+                EventKind::PrintPaused,
+                EventKind::PrintResumed,
+                EventKind::PrintFinished,
+                EventKind::LowFilament
+            ]
+        );
+        // the simulated benchy is 2 m, about 6 g
+        let remaining = spools.get(spool.id).unwrap().unwrap().remaining_grams;
+        assert_eq!(remaining.round(), 99.);
+        let prints = history.prints(Some("sim"), None).unwrap();
+        assert_eq!(prints.len(), 1);
+        assert_eq!(prints[0].outcome, Outcome::Finished);
+        assert_eq!(prints[0].filament_length, Some(2000.));
+
+        printer.start_job("").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        printer.fail("Thermal Runaway");
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let prints = history.prints(Some("sim"), None).unwrap();
+        assert_eq!(prints[0].outcome, Outcome::Failed);
+        assert_eq!(prints[0].error.as_deref(), Some("Thermal Runaway"));
+
+        checker.abort();
+    }
 }
@@ -1,6 +1,21 @@
+pub mod auth;
+pub mod config;
+pub mod confirmation;
 pub mod data_defs;
+pub mod events;
+pub mod filament_change;
 pub mod filaments;
+pub mod history;
+pub mod idempotency;
 pub mod job_checker;
+pub mod loaded_filament;
+pub mod mock_octoprint;
+pub mod notify_router;
+pub mod printer_registry;
 pub mod remote;
+pub mod routes;
+pub mod simulated_printer;
+pub mod spools;
+pub mod storage;
 pub mod traits;
 pub mod utils;
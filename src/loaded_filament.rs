@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::storage::Database;
+use crate::utils::time_utils::Time;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadedFilament {
+    /// name of the profile it was loaded with
+    pub filament: String,
+    pub loaded_at: DateTime<Utc>,
+}
+
+impl LoadedFilament {
+    /// For Siri, e.g. "PETG is loaded, it went in 2 hours and 10 minutes ago"
+    pub fn summary(&self, now: DateTime<Utc>) -> String {
+        match Time::from_seconds((now - self.loaded_at).num_seconds()) {
+            Some(time) => format!(
+                "{} is loaded, it went in {} ago",
+                self.filament,
+                time.to_human_readable_briefly()
+            ),
+            None => format!(
+                "{} is loaded, it went in on {}",
+                self.filament,
+                self.loaded_at.with_timezone(&Local).format("%B %-d")
+            ),
+        }
+    }
+}
+
+/// Which filament each printer has, as far as loading and unloading through here goes.
+/// Filament changed by hand at the printer isn't noticed.
+pub struct LoadedFilaments {
+    db: Arc<Database>,
+}
+
+impl LoadedFilaments {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self { db }
+    }
+
+    /// `None` if it was unloaded, or never loaded through here
+    pub fn get(&self, printer: &str) -> anyhow::Result<Option<LoadedFilament>> {
+        self.db.with_conn(|conn| {
+            conn.query_row(
+                "SELECT filament, loaded_at FROM loaded_filament WHERE printer = ?1",
+                params![printer],
+                |row| {
+                    Ok(LoadedFilament {
+                        filament: row.get(0)?,
+                        loaded_at: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+        })
+    }
+
+    pub fn loaded(&self, printer: &str, filament: &str, at: DateTime<Utc>) -> anyhow::Result<()> {
+        self.db.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO loaded_filament (printer, filament, loaded_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT (printer) DO UPDATE SET filament = ?2, loaded_at = ?3",
+                params![printer, filament, at],
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn unloaded(&self, printer: &str) -> anyhow::Result<()> {
+        self.db.with_conn(|conn| {
+            conn.execute(
+                "DELETE FROM loaded_filament WHERE printer = ?1",
+                params![printer],
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    #[test]
+    fn test_loaded_filament() {
+        let loaded = LoadedFilaments::new(Arc::new(Database::open_in_memory().unwrap()));
+        let now = Utc.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap();
+        assert_eq!(loaded.get("ender").unwrap(), None);
+
+        loaded
+            .loaded("ender", "PLA", now - Duration::days(1))
+            .unwrap();
+        loaded.loaded("ender", "PETG", now).unwrap();
+        loaded.loaded("prusa", "TPU", now).unwrap();
+        let petg = loaded.get("ender").unwrap().unwrap();
+        assert_eq!(
+            petg,
+            LoadedFilament {
+                filament: "PETG".to_string(),
+                loaded_at: now,
+            }
+        );
+        assert_eq!(
+            petg.summary(now + Duration::minutes(130)),
+            "PETG is loaded, it went in 2 hours and 10 minutes ago"
+        );
+        assert!(petg
+            .summary(now + Duration::days(30))
+            .starts_with("PETG is loaded, it went in on "));
+
+        loaded.unloaded("ender").unwrap();
+        assert_eq!(loaded.get("ender").unwrap(), None);
+        assert!(loaded.get("prusa").unwrap().is_some());
+    }
+}
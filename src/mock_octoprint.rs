@@ -0,0 +1,180 @@
+//! A fake OctoPrint for developing and testing without a real printer.
+//!
+//! Only the parts of the api this server uses are implemented,
+//! on top of a `SimulatedPrinter` sped up by `speed`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use actix_web::{dev::Server, get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use log::info;
+use serde_json::json;
+
+use crate::data_defs::printer_job_action::JobAction;
+use crate::data_defs::printer_move::{HomeAxis, PrinterMove};
+use crate::data_defs::printer_tool::Tool;
+use crate::simulated_printer::{SimState, SimulatedPrinter};
+
+/// The mock printer shared by all requests
+pub struct MockOctoPrint {
+    api_key: String,
+    pub printer: SimulatedPrinter,
+}
+
+impl MockOctoPrint {
+    pub fn new(api_key: impl Into<String>, speed: f64) -> Self {
+        Self {
+            api_key: api_key.into(),
+            printer: SimulatedPrinter::new(speed),
+        }
+    }
+
+    fn check_key(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        match req.headers().get("X-Api-Key") {
+            Some(key) if key.as_bytes() == self.api_key.as_bytes() => Ok(()),
+            _ => Err(HttpResponse::Forbidden().body("Invalid API key")),
+        }
+    }
+}
+
+fn command_response(result: Result<(), &'static str>) -> HttpResponse {
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(reason) => HttpResponse::Conflict().body(reason),
+    }
+}
+
+/// Runs `f` on the simulation if the request has the right api key
+fn with_state(
+    mock: &MockOctoPrint,
+    req: &HttpRequest,
+    f: impl FnOnce(&mut SimState) -> HttpResponse,
+) -> HttpResponse {
+    if let Err(response) = mock.check_key(req) {
+        return response;
+    }
+    mock.printer.update(f)
+}
+
+#[get("/api/version")]
+async fn version(mock: web::Data<MockOctoPrint>, req: HttpRequest) -> HttpResponse {
+    with_state(&mock, &req, |_| {
+        HttpResponse::Ok().json(json!({
+            "api": "0.1",
+            "server": "1.9.3",
+            "text": "OctoPrint 1.9.3 (mock)",
+        }))
+    })
+}
+
+#[get("/api/printer")]
+async fn printer(mock: web::Data<MockOctoPrint>, req: HttpRequest) -> HttpResponse {
+    with_state(&mock, &req, |state| {
+        HttpResponse::Ok().json(state.printer_state())
+    })
+}
+
+#[get("/api/job")]
+async fn job(mock: web::Data<MockOctoPrint>, req: HttpRequest) -> HttpResponse {
+    with_state(&mock, &req, |state| {
+        HttpResponse::Ok().json(state.job_state())
+    })
+}
+
+#[post("/api/job")]
+async fn job_command(
+    mock: web::Data<MockOctoPrint>,
+    req: HttpRequest,
+    command: web::Json<JobAction>,
+) -> HttpResponse {
+    with_state(&mock, &req, |state| {
+        info!("Mock printer: {:?}", command);
+        command_response(state.job_command(command.into_inner()))
+    })
+}
+
+#[post("/api/printer/tool")]
+async fn tool_command(
+    mock: web::Data<MockOctoPrint>,
+    req: HttpRequest,
+    command: web::Json<Tool>,
+) -> HttpResponse {
+    with_state(&mock, &req, |state| {
+        info!("Mock printer: {:?}", command);
+        command_response(state.tool_command(command.into_inner()))
+    })
+}
+
+#[post("/api/printer/printhead")]
+async fn printhead_command(
+    mock: web::Data<MockOctoPrint>,
+    req: HttpRequest,
+    command: web::Json<PrinterMove>,
+) -> HttpResponse {
+    with_state(&mock, &req, |state| {
+        info!("Mock printer: {:?}", command);
+        command_response(state.printhead_command(command.into_inner()))
+    })
+}
+
+/// Only `M410`, `G60` and `G61` do anything, other G-code is just logged
+#[post("/api/printer/command")]
+async fn gcode_command(
+    mock: web::Data<MockOctoPrint>,
+    req: HttpRequest,
+    command: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    with_state(&mock, &req, |state| {
+        info!("Mock printer: {}", command);
+        let commands = command["commands"].as_array().cloned().unwrap_or_default();
+        for line in commands.iter().filter_map(|c| c.as_str()) {
+            let mut words = line.split_whitespace();
+            let result = match words.next() {
+                Some("M410") => state.quick_stop(),
+                Some("G60") => state.save_position(),
+                Some("G61") => {
+                    let axes: Vec<_> = words
+                        .filter_map(|word| match word {
+                            "X" => Some(HomeAxis::X),
+                            "Y" => Some(HomeAxis::Y),
+                            "Z" => Some(HomeAxis::Z),
+                            _ => None,
+                        })
+                        .collect();
+                    state.restore_position(&axes)
+                }
+                _ => Ok(()),
+            };
+            if result.is_err() {
+                return command_response(result);
+            }
+        }
+        HttpResponse::NoContent().finish()
+    })
+}
+
+/// Needs a `web::Data<MockOctoPrint>` in the app data
+pub fn mock_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(version)
+        .service(printer)
+        .service(job)
+        .service(job_command)
+        .service(tool_command)
+        .service(printhead_command)
+        .service(gcode_command);
+}
+
+/// Binds the mock to `addr` (port 0 picks a free one).
+/// The returned server has to be awaited or spawned to run.
+pub fn start(mock: Arc<MockOctoPrint>, addr: SocketAddr) -> std::io::Result<(SocketAddr, Server)> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::from(mock.clone()))
+            .configure(mock_routes)
+    })
+    .workers(1)
+    .bind(addr)?;
+
+    let addr = server.addrs()[0];
+    Ok((addr, server.run()))
+}
@@ -0,0 +1,254 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::NaiveTime;
+use log::info;
+
+use crate::config::{QuietHoursConfig, QuietMode, RouteConfig};
+use crate::events::PrinterEvent;
+use crate::traits::notify_trait::Notifier;
+use crate::utils::logging_util::LoggableResult;
+
+/// A dead endpoint shouldn't hold up the job checker for long
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Target {
+    name: String,
+    notifier: Box<dyn Notifier>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dispatch {
+    Now,
+    Later(Duration),
+    Drop,
+}
+
+/// Fans events out to many notifiers, filtered by routes and quiet hours.
+///
+/// Every notifier is called on its own task with a timeout,
+/// so one failing or hanging endpoint doesn't affect the others.
+pub struct NotifyRouter {
+    targets: Vec<Arc<Target>>,
+    routes: Vec<RouteConfig>,
+    quiet_hours: Option<QuietHoursConfig>,
+}
+
+impl NotifyRouter {
+    pub fn new(routes: Vec<RouteConfig>, quiet_hours: Option<QuietHoursConfig>) -> Self {
+        Self {
+            targets: Vec::new(),
+            routes,
+            quiet_hours,
+        }
+    }
+
+    /// `name` is what routes refer to
+    pub fn add(&mut self, name: impl Into<String>, notifier: Box<dyn Notifier>) {
+        self.targets.push(Arc::new(Target {
+            name: name.into(),
+            notifier,
+        }));
+    }
+
+    /// A notifier without any routes gets everything
+    fn wants(&self, target: &str, printer_id: &str, event: &PrinterEvent, now: NaiveTime) -> bool {
+        let mut routes = self
+            .routes
+            .iter()
+            .filter(|route| route.notifier == target)
+            .peekable();
+
+        if routes.peek().is_none() {
+            return true;
+        }
+
+        routes.any(|route| {
+            route
+                .events
+                .as_ref()
+                .is_none_or(|events| events.contains(&event.kind()))
+                && route
+                    .printers
+                    .as_ref()
+                    .is_none_or(|printers| printers.iter().any(|p| p == printer_id))
+                && route.hours.is_none_or(|hours| hours.contains(now))
+        })
+    }
+
+    fn dispatch(&self, event: &PrinterEvent, now: NaiveTime) -> Dispatch {
+        match &self.quiet_hours {
+            Some(quiet_hours) if !event.is_critical() && quiet_hours.window.contains(now) => {
+                match quiet_hours.mode {
+                    QuietMode::Suppress => Dispatch::Drop,
+                    QuietMode::Delay => Dispatch::Later(quiet_hours.window.remaining(now)),
+                }
+            }
+            _ => Dispatch::Now,
+        }
+    }
+}
+
+async fn send(target: Arc<Target>, printer_id: String, event: PrinterEvent) -> anyhow::Result<()> {
+    tokio::time::timeout(NOTIFY_TIMEOUT, target.notifier.notify(&printer_id, &event))
+        .await
+        .map_err(|_| anyhow!("{} timed out", target.name))?
+        .map_err(|e| anyhow!("{}: {}", target.name, e))
+        .log_error()
+}
+
+#[async_trait::async_trait]
+impl Notifier for NotifyRouter {
+    async fn notify(&self, printer_id: &str, event: &PrinterEvent) -> anyhow::Result<()> {
+        let now = chrono::Local::now().time();
+        let dispatch = self.dispatch(event, now);
+
+        let mut handles = Vec::new();
+        for target in &self.targets {
+            if !self.wants(&target.name, printer_id, event, now) {
+                continue;
+            }
+
+            let task = send(target.clone(), printer_id.to_string(), event.clone());
+            match dispatch {
+                Dispatch::Drop => {
+                    info!(
+                        "Quiet hours, not sending {:?} to {}",
+                        event.kind(),
+                        target.name
+                    );
+                }
+                Dispatch::Later(delay) => {
+                    info!(
+                        "Quiet hours, sending {:?} to {} in {} minutes",
+                        event.kind(),
+                        target.name,
+                        delay.as_secs() / 60
+                    );
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        task.await
+                    });
+                }
+                Dispatch::Now => handles.push(tokio::spawn(task)),
+            }
+        }
+
+        let mut errors = Vec::new();
+        for handle in handles {
+            match handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => errors.push(e.to_string()),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        anyhow::ensure!(errors.is_empty(), "Failed to notify {}", errors.join(", "));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::events::{EventKind, PrintInfo};
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn finished() -> PrinterEvent {
+        PrinterEvent::PrintFinished(PrintInfo::default())
+    }
+
+    fn failed() -> PrinterEvent {
+        PrinterEvent::PrintFailed {
+            print: PrintInfo::default(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        let router = NotifyRouter::new(
+            vec![
+                RouteConfig {
+                    notifier: "phone".to_string(),
+                    events: Some(vec![EventKind::PrintFinished]),
+                    printers: Some(vec!["ender".to_string()]),
+                    hours: None,
+                },
+                RouteConfig {
+                    notifier: "phone".to_string(),
+                    events: Some(vec![EventKind::PrintFailed]),
+                    printers: None,
+                    hours: Some("08:00-22:00".parse().unwrap()),
+                },
+            ],
+            None,
+        );
+
+        assert!(router.wants("phone", "ender", &finished(), at(12, 0)));
+        assert!(!router.wants("phone", "prusa", &finished(), at(12, 0)));
+        assert!(router.wants("phone", "prusa", &failed(), at(12, 0)));
+        assert!(!router.wants("phone", "prusa", &failed(), at(23, 0)));
+        // no routes at all, so everything
+        assert!(router.wants("doorbell", "prusa", &failed(), at(23, 0)));
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet_hours = |mode| {
+            Some(QuietHoursConfig {
+                window: "22:00-07:00".parse().unwrap(),
+                mode,
+            })
+        };
+
+        let router = NotifyRouter::new(vec![], quiet_hours(QuietMode::Suppress));
+        assert_eq!(router.dispatch(&finished(), at(12, 0)), Dispatch::Now);
+        assert_eq!(router.dispatch(&finished(), at(23, 0)), Dispatch::Drop);
+        assert_eq!(router.dispatch(&failed(), at(23, 0)), Dispatch::Now);
+
+        let router = NotifyRouter::new(vec![], quiet_hours(QuietMode::Delay));
+        assert_eq!(
+            router.dispatch(&finished(), at(6, 0)),
+            Dispatch::Later(Duration::from_secs(3600))
+        );
+    }
+
+    struct Recording(Arc<Mutex<Vec<EventKind>>>);
+
+    #[async_trait::async_trait]
+    impl Notifier for Recording {
+        async fn notify(&self, _printer_id: &str, event: &PrinterEvent) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(event.kind());
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait::async_trait]
+    impl Notifier for Failing {
+        async fn notify(&self, _printer_id: &str, _event: &PrinterEvent) -> anyhow::Result<()> {
+            anyhow::bail!("endpoint is down")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failure_is_independent() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let mut router = NotifyRouter::new(vec![], None);
+        router.add("dead", Box::new(Failing));
+        router.add("alive", Box::new(Recording(received.clone())));
+
+        let err = router.notify("ender", &finished()).await.unwrap_err();
+        assert!(err.to_string().contains("dead"), "{}", err);
+        assert_eq!(*received.lock().unwrap(), vec![EventKind::PrintFinished]);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::{web, FromRequest, HttpRequest};
+use tokio::sync::Mutex;
+
+use crate::config::{Backend, Config, HeatingConfig, ParkPosition};
+use crate::remote::marlin_service::MarlinService;
+use crate::remote::moonraker_service::MoonrakerService;
+use crate::remote::printer_service::PrinterService;
+use crate::remote::prusalink_service::PrusaLinkService;
+use crate::traits::printer_trait::{Capability, Printer};
+use crate::utils::http_errors::AnyhowHTTPError;
+use crate::utils::job_running::LongRunningJob;
+
+/// Name of the path segment used by the `/printers/{printer_id}/...` routes
+pub const PRINTER_ID_PARAM: &str = "printer_id";
+
+/// Everything that belongs to a single physical printer
+pub struct PrinterEntry {
+    pub id: String,
+    pub printer: Arc<dyn Printer>,
+    pub long_running_job: Arc<Mutex<LongRunningJob>>,
+    /// the key for the printer's own api, for the routes as well as the job checker.
+    /// Clients never see it.
+    pub api_key: String,
+    /// limits for heating up before a filament change
+    pub heating: HeatingConfig,
+    /// where the print head waits during a change mid-print
+    pub park: ParkPosition,
+}
+
+impl PrinterEntry {
+    /// A 501 that Siri can read out if the backend can't do this
+    pub fn require(&self, capability: Capability) -> Result<(), AnyhowHTTPError> {
+        if self.printer.capabilities().has(capability) {
+            return Ok(());
+        }
+        Err(AnyhowHTTPError::NotImplemented501(format!(
+            "Printer {} can't {}",
+            self.id,
+            capability.spoken()
+        )))
+    }
+}
+
+pub struct PrinterRegistry {
+    printers: BTreeMap<String, Arc<PrinterEntry>>,
+    default_id: String,
+    /// for printers inserted from now on
+    heating: HeatingConfig,
+}
+
+impl PrinterRegistry {
+    pub fn new(default_id: impl Into<String>) -> Self {
+        Self {
+            printers: BTreeMap::new(),
+            default_id: default_id.into(),
+            heating: HeatingConfig::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config, client: &reqwest::Client) -> Self {
+        let mut registry = Self::new(config.default_printer.clone());
+        registry.heating = config.heating.clone();
+        for (id, printer_config) in &config.printers {
+            let base_url = || {
+                printer_config
+                    .base_url
+                    .as_ref()
+                    .expect("config validation requires a base_url")
+            };
+            let printer: Arc<dyn Printer> = match printer_config.backend {
+                Backend::OctoPrint => Arc::new(PrinterService::new(client.clone(), base_url())),
+                Backend::Moonraker => Arc::new(MoonrakerService::new(client.clone(), base_url())),
+                Backend::PrusaLink => {
+                    let credentials = printer_config
+                        .credentials
+                        .clone()
+                        .expect("config validation requires prusalink credentials");
+                    Arc::new(PrusaLinkService::new(
+                        client.clone(),
+                        base_url(),
+                        credentials.username,
+                        credentials.password,
+                    ))
+                }
+                Backend::Marlin => {
+                    let serial = printer_config
+                        .serial
+                        .clone()
+                        .expect("config validation requires a marlin serial port");
+                    Arc::new(MarlinService::new(serial.port, serial.baud_rate))
+                }
+            };
+            registry.insert(
+                id.clone(),
+                printer,
+                printer_config.api_key.clone(),
+                printer_config.park,
+            );
+        }
+        registry
+    }
+
+    pub fn insert(
+        &mut self,
+        id: String,
+        printer: Arc<dyn Printer>,
+        api_key: String,
+        park: ParkPosition,
+    ) {
+        let entry = PrinterEntry {
+            id: id.clone(),
+            printer,
+            long_running_job: Arc::new(Mutex::new(LongRunningJob::default())),
+            api_key,
+            heating: self.heating.clone(),
+            park,
+        };
+        self.printers.insert(id, Arc::new(entry));
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Arc<PrinterEntry>> {
+        self.printers.get(id)
+    }
+
+    pub fn default_id(&self) -> &str {
+        &self.default_id
+    }
+
+    pub fn default_printer(&self) -> Option<&Arc<PrinterEntry>> {
+        self.get(&self.default_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<PrinterEntry>> {
+        self.printers.values()
+    }
+}
+
+/// Extracts the printer named by the `{printer_id}` path segment,
+/// or the default printer for routes that don't have one.
+pub struct SelectedPrinter(pub Arc<PrinterEntry>);
+
+impl std::ops::Deref for SelectedPrinter {
+    type Target = PrinterEntry;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for SelectedPrinter {
+    type Error = AnyhowHTTPError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(select_printer(req))
+    }
+}
+
+fn select_printer(req: &HttpRequest) -> Result<SelectedPrinter, AnyhowHTTPError> {
+    let registry = req
+        .app_data::<web::Data<PrinterRegistry>>()
+        .ok_or_else(|| {
+            AnyhowHTTPError::InternalServerError500("Printer registry not configured".to_string())
+        })?;
+
+    let entry = match req.match_info().get(PRINTER_ID_PARAM) {
+        Some(id) => registry
+            .get(id)
+            .ok_or_else(|| AnyhowHTTPError::NotFound404(format!("No printer named {}", id)))?,
+        None => registry.default_printer().ok_or_else(|| {
+            AnyhowHTTPError::InternalServerError500(format!(
+                "Default printer {} not found",
+                registry.default_id()
+            ))
+        })?,
+    };
+
+    Ok(SelectedPrinter(entry.clone()))
+}
@@ -0,0 +1,84 @@
+//! G-code for the backends that talk to the firmware more or less directly.
+//! Commands are separated by newlines.
+
+use crate::data_defs::printer_move::{HomeAxis, PrinterMove};
+use crate::data_defs::printer_tool::Tool;
+
+/// Marlin keeps it in slot 0 until `RESTORE_POSITION`, if it's built with `SAVED_POSITIONS`
+pub const SAVE_POSITION: &str = "G60 S0";
+/// X and Y first, so that the print head doesn't come down on the print
+pub const RESTORE_POSITION: &str = "G61 S0 X Y\nG61 S0 Z";
+
+pub fn tool_gcode(command: &Tool) -> String {
+    match command {
+        Tool::Target { targets } => format!("M104 S{}", targets.tool0),
+        Tool::Extrude { amount, speed } => {
+            let feed_rate = speed.map(|s| format!(" F{}", s)).unwrap_or_default();
+            // relative extrusion, so the amount isn't added to the current position
+            format!("M83\nG1 E{}{}", amount, feed_rate)
+        }
+    }
+}
+
+pub fn printhead_gcode(command: &PrinterMove) -> String {
+    match command {
+        PrinterMove::Home { axes } => {
+            let axes: Vec<_> = axes
+                .iter()
+                .map(|axis| match axis {
+                    HomeAxis::X => "X",
+                    HomeAxis::Y => "Y",
+                    HomeAxis::Z => "Z",
+                })
+                .collect();
+            format!("G28 {}", axes.join(" "))
+        }
+        PrinterMove::Move {
+            x,
+            y,
+            z,
+            absolute,
+            speed,
+        } => {
+            let mut g1 = "G1".to_string();
+            for (axis, value) in [("X", x), ("Y", y), ("Z", z), ("F", speed)] {
+                if let Some(value) = value {
+                    g1.push_str(&format!(" {}{}", axis, value));
+                }
+            }
+            // like OctoPrint, moves are relative unless asked otherwise,
+            // and the printer is left in absolute mode afterwards
+            match absolute {
+                Some(true) => format!("G90\n{}", g1),
+                _ => format!("G91\n{}\nG90", g1),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gcode() {
+        assert_eq!(
+            tool_gcode(&Tool::Extrude {
+                amount: -450.,
+                speed: Some(250.)
+            }),
+            "M83\nG1 E-450 F250"
+        );
+        assert_eq!(printhead_gcode(&PrinterMove::home_all()), "G28 X Y Z");
+        assert_eq!(
+            printhead_gcode(&PrinterMove::Move {
+                x: None,
+                y: None,
+                z: Some(200.),
+                absolute: Some(true),
+                speed: None
+            }),
+            "G90\nG1 Z200"
+        );
+    }
+}
@@ -0,0 +1,749 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use log::{debug, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use super::gcode::{printhead_gcode, tool_gcode, RESTORE_POSITION, SAVE_POSITION};
+use crate::data_defs::printer_job_action::PauseAction;
+use crate::data_defs::printer_job_state::{self, JobState};
+use crate::data_defs::printer_move::PrinterMove;
+use crate::data_defs::printer_state::{self, PrinterState};
+use crate::data_defs::printer_tool::Tool;
+use crate::traits::printer_trait::{Capabilities, Printer};
+use crate::utils::http_errors::AnyhowHTTPError;
+
+/// Marlin sends `busy` every couple of seconds during long moves,
+/// so a silent printer is a dead printer
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the answer to `M110` while the board boots
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+const HANDSHAKE_ATTEMPTS: usize = 5;
+
+/// Talks G-code to Marlin over a serial port, without OctoPrint in between.
+///
+/// Prints run from the printer's SD card: `start_job` prints the file selected on the printer.
+/// Nothing is sent while a print runs except status queries and job control,
+/// as streaming a file over serial is OctoPrint's job.
+/// There is no api key, the api key arguments are ignored.
+pub struct MarlinService {
+    port: String,
+    baud_rate: u32,
+    /// opened on first use and dropped after an error, so the next command reconnects
+    connection: Mutex<Option<Connection>>,
+    /// Marlin reports a paused SD print like one that hasn't started,
+    /// so remember whether it was paused from here
+    paused: AtomicBool,
+}
+
+struct Connection {
+    stream: BufReader<SerialStream>,
+    line_number: u64,
+}
+
+/// XOR of all bytes, as Marlin expects after the `*`
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+/// `N<n> <command>*<checksum>`
+pub fn numbered_line(line_number: u64, command: &str) -> String {
+    let line = format!("N{} {}", line_number, command);
+    let checksum = checksum(&line);
+    format!("{}*{}", line, checksum)
+}
+
+/// The line Marlin wants again from `Resend: 5` or `rs N5`
+fn resend_request(line: &str) -> Option<u64> {
+    let rest = line
+        .strip_prefix("Resend:")
+        .or_else(|| line.strip_prefix("rs"))?;
+    rest.trim().trim_start_matches('N').parse().ok()
+}
+
+impl Connection {
+    async fn open(port: &str, baud_rate: u32) -> anyhow::Result<Self> {
+        let stream = tokio_serial::new(port, baud_rate).open_native_async()?;
+        let mut connection = Self {
+            stream: BufReader::new(stream),
+            line_number: 0,
+        };
+        connection.handshake().await?;
+        Ok(connection)
+    }
+
+    /// Most boards reset when the port opens and ignore everything until they have booted,
+    /// so reset the line numbers until the printer answers
+    async fn handshake(&mut self) -> anyhow::Result<()> {
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            self.line_number = 0;
+            self.write_line("M110 N0").await?;
+            // anything else is the board booting
+            while let Ok(line) = timeout(HANDSHAKE_TIMEOUT, self.read_line()).await {
+                if line?.starts_with("ok") {
+                    return Ok(());
+                }
+            }
+        }
+        bail!("Printer didn't answer")
+    }
+
+    async fn write_line(&mut self, command: &str) -> anyhow::Result<()> {
+        let line = numbered_line(self.line_number, command);
+        debug!("Marlin <- {}", line);
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            bail!("Serial port closed");
+        }
+        let line = line.trim().to_string();
+        debug!("Marlin -> {}", line);
+        Ok(line)
+    }
+
+    /// Sends a single command and returns what the printer said before (and on) its `ok`
+    async fn command(&mut self, command: &str) -> anyhow::Result<Vec<String>> {
+        self.line_number += 1;
+        self.write_line(command).await?;
+
+        let mut response = Vec::new();
+        let mut resend = false;
+        loop {
+            let line = timeout(RESPONSE_TIMEOUT, self.read_line())
+                .await
+                .map_err(|_| anyhow!("Printer didn't answer {:?}", command))??;
+
+            if let Some(rest) = line.strip_prefix("ok") {
+                if resend {
+                    resend = false;
+                    self.write_line(command).await?;
+                    continue;
+                }
+                if !rest.trim().is_empty() {
+                    response.push(rest.trim().to_string());
+                }
+                return Ok(response);
+            } else if let Some(requested) = resend_request(&line) {
+                // only one line is ever in flight, so that is the only one that can be asked for
+                if requested != self.line_number {
+                    bail!(
+                        "Printer asked for line {} again, but line {} was sent",
+                        requested,
+                        self.line_number
+                    );
+                }
+                warn!("Marlin wants line {} again", requested);
+                resend = true;
+            } else if line.starts_with("echo:busy") {
+                continue;
+            } else if line == "start" {
+                bail!("Printer restarted");
+            } else if line.starts_with("Error:")
+                && (line.contains("halted") || line.contains("kill"))
+            {
+                bail!("Printer halted: {}", line.trim_start_matches("Error:"));
+            } else if !line.starts_with("Error:") {
+                // errors without a resend request are followed by another line saying what to do
+                response.push(line);
+            }
+        }
+    }
+}
+
+/// From `T:210.0 /210.0 B:60.0 /60.0 @:127 B@:0`, which may follow an `ok`
+pub fn parse_temperatures(line: &str) -> Option<printer_state::Temperature> {
+    let tokens: Vec<_> = line.split_whitespace().collect();
+    let reading = |names: &[&str]| {
+        let i = tokens.iter().position(
+            |token| matches!(token.split_once(':'), Some((name, _)) if names.contains(&name)),
+        )?;
+        let actual = tokens[i].split_once(':')?.1;
+        let actual: f64 = match actual {
+            // some versions put a space after the colon
+            "" => tokens.get(i + 1)?.parse().ok()?,
+            actual => actual.parse().ok()?,
+        };
+        let target = tokens[i + 1..]
+            .iter()
+            .take(2)
+            .find_map(|token| token.strip_prefix('/'))
+            .and_then(|target| target.parse().ok())
+            .unwrap_or(0.);
+        Some((actual, target))
+    };
+
+    // multi extruder printers report `T0:` as well as `T:` for the active one
+    let (tool_actual, tool_target) = reading(&["T", "T0"])?;
+    let (bed_actual, bed_target) = reading(&["B"]).unwrap_or_default();
+    Some(printer_state::Temperature {
+        bed: printer_state::Bed {
+            actual: bed_actual,
+            offset: 0,
+            target: bed_target,
+        },
+        tool0: printer_state::Tool0 {
+            actual: tool_actual,
+            offset: 0,
+            target: tool_target,
+        },
+    })
+}
+
+/// What `M27` and `M27 C` say about the SD card
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct SdStatus {
+    /// `(position, size)` in bytes while printing
+    pub progress: Option<(i64, i64)>,
+    /// the open file, which is either printing, paused or selected with `M23`
+    pub file: Option<String>,
+    /// in seconds, from `M31`
+    pub print_time: Option<i64>,
+}
+
+impl SdStatus {
+    fn parse(lines: &[String]) -> Self {
+        let mut status = Self::default();
+        for line in lines {
+            if let Some(bytes) = line.strip_prefix("SD printing byte ") {
+                status.progress = bytes.split_once('/').and_then(|(pos, size)| {
+                    Some((pos.trim().parse().ok()?, size.trim().parse().ok()?))
+                });
+            } else if let Some(file) = line.strip_prefix("Current file: ") {
+                // short 8.3 name first, then the long name if there is one
+                let file = file.trim();
+                if file != "(no file)" {
+                    status.file = Some(
+                        file.split_once(' ')
+                            .map_or(file, |(_, long)| long)
+                            .to_string(),
+                    );
+                }
+            } else if let Some(time) = line.strip_prefix("echo:Print time: ") {
+                status.print_time = parse_duration(time);
+            }
+        }
+        status
+    }
+}
+
+/// `1d 2h 3m 4s`, any part may be missing
+fn parse_duration(text: &str) -> Option<i64> {
+    text.split_whitespace().try_fold(0, |total, part| {
+        let (value, unit) = part.split_at(part.len().checked_sub(1)?);
+        let value: i64 = value.parse().ok()?;
+        let seconds = match unit {
+            "d" => 86400,
+            "h" => 3600,
+            "m" => 60,
+            "s" => 1,
+            _ => return None,
+        };
+        Some(total + value * seconds)
+    })
+}
+
+pub fn printer_state_from(
+    temperature: printer_state::Temperature,
+    sd: &SdStatus,
+    paused: bool,
+) -> PrinterState {
+    let printing = sd.progress.is_some();
+    let paused = !printing && paused && sd.file.is_some();
+    PrinterState {
+        sd: printer_state::Sd { ready: true },
+        state: printer_state::State {
+            error: String::new(),
+            flags: printer_state::Flags {
+                cancelling: false,
+                closed_or_error: false,
+                error: false,
+                finishing: false,
+                operational: true,
+                paused,
+                pausing: false,
+                printing,
+                ready: !printing && !paused,
+                resuming: false,
+                sd_ready: true,
+            },
+            text: state_text(printing, paused).to_string(),
+        },
+        temperature,
+    }
+}
+
+fn state_text(printing: bool, paused: bool) -> &'static str {
+    match (printing, paused) {
+        (true, _) => "Printing from SD",
+        (_, true) => "Paused",
+        _ => "Operational",
+    }
+}
+
+pub fn job_state_from(sd: &SdStatus, paused: bool) -> JobState {
+    let printing = sd.progress.is_some();
+    let paused = !printing && paused && sd.file.is_some();
+    let completion = sd.progress.map(|(pos, size)| match size {
+        0 => 0.,
+        size => pos as f64 / size as f64 * 100.,
+    });
+    // Marlin has no estimate, so extrapolate like OctoPrint does without one
+    let print_time_left = match (completion, sd.print_time) {
+        (Some(completion), Some(print_time)) if completion >= 1. => {
+            Some((print_time as f64 * (100. - completion) / completion).round() as i64)
+        }
+        _ => None,
+    };
+
+    JobState {
+        job: printer_job_state::Job {
+            file: printer_job_state::File {
+                name: sd.file.clone(),
+                origin: sd.file.as_ref().map(|_| "sdcard".to_string()),
+                size: sd.progress.map(|(_, size)| size),
+                date: None,
+            },
+            ..Default::default()
+        },
+        progress: printer_job_state::Progress {
+            completion,
+            filepos: sd.progress.map(|(pos, _)| pos),
+            print_time: sd.print_time.filter(|_| printing || paused),
+            print_time_left,
+        },
+        state: state_text(printing, paused).to_string(),
+        error: None,
+    }
+}
+
+fn conflict(message: &str) -> anyhow::Error {
+    AnyhowHTTPError::Conflict409(message.to_string()).into()
+}
+
+impl MarlinService {
+    pub fn new(port: impl Into<String>, baud_rate: u32) -> Self {
+        Self {
+            port: port.into(),
+            baud_rate,
+            connection: Mutex::new(None),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Sends each line of `gcode` in turn, all responses together
+    async fn gcode(&self, gcode: &str) -> anyhow::Result<Vec<String>> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(Connection::open(&self.port, self.baud_rate).await?);
+        }
+
+        let mut response = Vec::new();
+        for line in gcode.lines() {
+            match connection.as_mut().unwrap().command(line).await {
+                Ok(lines) => response.extend(lines),
+                Err(e) => {
+                    *connection = None;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    async fn sd_status(&self) -> anyhow::Result<SdStatus> {
+        Ok(SdStatus::parse(&self.gcode("M27\nM27 C\nM31").await?))
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Moving or extruding during a print would ruin it.
+    /// While it's paused is fine, that's how filament gets changed mid-print.
+    async fn ensure_not_printing(&self) -> anyhow::Result<()> {
+        if self.sd_status().await?.progress.is_some() {
+            return Err(conflict("Printer is currently printing"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Printer for MarlinService {
+    fn capabilities(&self) -> Capabilities {
+        // files go onto the SD card by hand
+        Capabilities {
+            pause: true,
+            restart: true,
+            raw_extrusion: true,
+            bed_heater: true,
+            multiple_tools: false,
+            webcam: false,
+            file_upload: false,
+        }
+    }
+
+    async fn printer_state(&self, _api_key: &str) -> anyhow::Result<PrinterState> {
+        // an unreachable printer is an error, like with the other backends,
+        // so that the job checker reports the disconnect
+        let response = self.gcode("M105").await?;
+        let temperature = response
+            .iter()
+            .find_map(|line| parse_temperatures(line))
+            .ok_or_else(|| anyhow!("Printer didn't report temperatures"))?;
+        let sd = self.sd_status().await?;
+        Ok(printer_state_from(temperature, &sd, self.is_paused()))
+    }
+
+    async fn job_state(&self, _api_key: &str) -> anyhow::Result<JobState> {
+        let sd = self.sd_status().await?;
+        Ok(job_state_from(&sd, self.is_paused()))
+    }
+
+    async fn tool_command(&self, _api_key: &str, command: Tool) -> anyhow::Result<()> {
+        if let Tool::Extrude { .. } = command {
+            self.ensure_not_printing().await?;
+        }
+        let response = self.gcode(&tool_gcode(&command)).await?;
+        if response
+            .iter()
+            .any(|line| line.contains("cold extrusion prevented"))
+        {
+            return Err(conflict("Hot end is too cold to extrude"));
+        }
+        Ok(())
+    }
+
+    async fn printhead_command(&self, _api_key: &str, command: PrinterMove) -> anyhow::Result<()> {
+        self.ensure_not_printing().await?;
+        self.gcode(&printhead_gcode(&command)).await?;
+        Ok(())
+    }
+
+    async fn save_position(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.ensure_not_printing().await?;
+        let response = self.gcode(SAVE_POSITION).await?;
+        // restoring would fail the same way, so better not move the print head at all
+        if response.iter().any(|line| line.contains("Unknown command")) {
+            return Err(AnyhowHTTPError::NotImplemented501(
+                "The firmware can't save the print head's position, it needs SAVED_POSITIONS"
+                    .to_string(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn restore_position(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.ensure_not_printing().await?;
+        self.gcode(RESTORE_POSITION).await?;
+        Ok(())
+    }
+
+    async fn quick_stop(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.gcode("M410").await?;
+        Ok(())
+    }
+
+    async fn cancel_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        let sd = self.sd_status().await?;
+        if sd.progress.is_none() && !(self.is_paused() && sd.file.is_some()) {
+            return Err(conflict("Printer is neither printing nor paused"));
+        }
+        self.gcode("M524").await?;
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn start_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        let sd = self.sd_status().await?;
+        if sd.progress.is_some() || (self.is_paused() && sd.file.is_some()) {
+            return Err(conflict("Printer is busy"));
+        }
+        if sd.file.is_none() {
+            return Err(conflict("No file selected"));
+        }
+        self.gcode("M24").await?;
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn restart_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        let sd = self.sd_status().await?;
+        if !(self.is_paused() && sd.file.is_some() && sd.progress.is_none()) {
+            return Err(conflict("Printer is not paused"));
+        }
+        // back to the start of the file
+        self.gcode("M26 S0\nM24").await?;
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn pause_job(&self, _api_key: &str, action: PauseAction) -> anyhow::Result<()> {
+        let sd = self.sd_status().await?;
+        let printing = sd.progress.is_some();
+        let paused = !printing && self.is_paused() && sd.file.is_some();
+
+        let pause = match action {
+            PauseAction::Pause => true,
+            PauseAction::Resume => false,
+            PauseAction::Toggle => printing,
+        };
+        match (pause, printing, paused) {
+            (true, true, _) => self.gcode("M25").await?,
+            (false, _, true) => self.gcode("M24").await?,
+            (true, false, _) => return Err(conflict("Printer is not printing")),
+            (false, _, false) => return Err(conflict("Printer is not paused")),
+        };
+        self.paused.store(pause, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio_serial::SerialPort;
+
+    use super::*;
+
+    #[test]
+    fn test_protocol() {
+        assert_eq!(numbered_line(1, "M105"), "N1 M105*38");
+        assert_eq!(resend_request("Resend: 5"), Some(5));
+        assert_eq!(resend_request("rs N12"), Some(12));
+        assert_eq!(resend_request("ok"), None);
+
+        let temperature = parse_temperatures("T:209.8 /210.0 B:60.1 /60.0 @:127 B@:0").unwrap();
+        assert_eq!(temperature.tool0.actual, 209.8);
+        assert_eq!(temperature.tool0.target, 210.);
+        assert_eq!(temperature.bed.actual, 60.1);
+        let temperature = parse_temperatures("T0:25.0 /0.0 T1:24.0 /0.0 B:22.0 /0.0").unwrap();
+        assert_eq!(temperature.tool0.actual, 25.);
+        assert!(parse_temperatures("echo:busy: processing").is_none());
+
+        assert_eq!(parse_duration("1h 2m 3s"), Some(3723));
+        assert_eq!(parse_duration("15m 0s"), Some(900));
+        assert_eq!(parse_duration("soon"), None);
+
+        let sd = SdStatus::parse(&[
+            "SD printing byte 250/1000".to_string(),
+            "Current file: BENCHY~1.GCO benchy.gcode".to_string(),
+            "echo:Print time: 15m 0s".to_string(),
+        ]);
+        let job = job_state_from(&sd, false);
+        assert_eq!(job.job.file.name.as_deref(), Some("benchy.gcode"));
+        assert_eq!(job.progress.completion, Some(25.));
+        assert_eq!(job.progress.print_time_left, Some(2700));
+
+        let selected = SdStatus::parse(&[
+            "Not SD printing".to_string(),
+            "Current file: BENCHY.GCO".to_string(),
+        ]);
+        let state = printer_state_from(temperature, &selected, false);
+        assert!(state.state.flags.ready && !state.state.flags.paused);
+        assert_eq!(job_state_from(&selected, true).state, "Paused");
+    }
+
+    /// Just enough of Marlin for the tests
+    #[derive(Default)]
+    struct FakeFirmware {
+        last_line: u64,
+        printing: bool,
+        file_open: bool,
+        pos: i64,
+        /// garble the next line with this number once
+        corrupt_line: Option<u64>,
+        /// built without `SAVED_POSITIONS`
+        no_g60: bool,
+        commands: Vec<String>,
+    }
+
+    impl FakeFirmware {
+        fn respond(&mut self, received: &str) -> Vec<String> {
+            let (line, sent_checksum) = received.rsplit_once('*').unwrap();
+            let (number, command) = line.split_once(' ').unwrap();
+            let number: u64 = number.trim_start_matches('N').parse().unwrap();
+
+            if self.corrupt_line == Some(number) {
+                self.corrupt_line = None;
+                return vec![
+                    format!("Error:checksum mismatch, Last Line: {}", self.last_line),
+                    format!("Resend: {}", number),
+                    "ok".to_string(),
+                ];
+            }
+            assert_eq!(sent_checksum.parse::<u8>().unwrap(), checksum(line));
+            if command.starts_with("M110") {
+                self.last_line = 0;
+                return vec!["ok".to_string()];
+            }
+            assert_eq!(number, self.last_line + 1, "{}", received);
+            self.last_line = number;
+            self.commands.push(command.to_string());
+
+            let mut response = Vec::new();
+            match command {
+                "M105" => return vec!["ok T:209.8 /210.0 B:60.0 /60.0 @:127 B@:0".to_string()],
+                "M27" if self.printing => {
+                    response.push(format!("SD printing byte {}/1000", self.pos))
+                }
+                "M27" => response.push("Not SD printing".to_string()),
+                "M27 C" if self.file_open => {
+                    response.push("Current file: BENCHY~1.GCO benchy.gcode".to_string())
+                }
+                "M27 C" => response.push("Current file: (no file)".to_string()),
+                "M31" => response.push("echo:Print time: 15m 0s".to_string()),
+                "M24" => {
+                    self.printing = self.file_open;
+                    self.pos = self.pos.max(250);
+                }
+                "M25" => self.printing = false,
+                "M524" => {
+                    self.printing = false;
+                    self.file_open = false;
+                }
+                c if c.starts_with("G28") => response.push("echo:busy: processing".to_string()),
+                c if c.starts_with("G60") && self.no_g60 => {
+                    response.push(format!("echo:Unknown command: \"{}\"", c))
+                }
+                _ => {}
+            }
+            response.push("ok".to_string());
+            response
+        }
+    }
+
+    /// Runs the fake firmware on one end of a pseudo-terminal, returns the path of the other end
+    fn start_fake_firmware(firmware: Arc<std::sync::Mutex<FakeFirmware>>) -> String {
+        let (master, slave) = SerialStream::pair().unwrap();
+        let path = slave.name().unwrap();
+        // the service opens the port itself, and can't while this holds the lock on it
+        drop(slave);
+
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(master);
+            let mut read = BufReader::new(read);
+            let mut booted = false;
+            loop {
+                let mut line = String::new();
+                match read.read_line(&mut line).await {
+                    Ok(0) => return,
+                    Ok(_) => {}
+                    // nobody has the port open yet
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        continue;
+                    }
+                }
+
+                let mut response = firmware.lock().unwrap().respond(line.trim());
+                if !booted {
+                    // like a board that resets when the port is opened
+                    response.insert(0, "start".to_string());
+                    booted = true;
+                }
+                for line in response {
+                    write
+                        .write_all(format!("{}\n", line).as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        path
+    }
+
+    #[tokio::test]
+    async fn test_fake_firmware() {
+        let firmware = Arc::new(std::sync::Mutex::new(FakeFirmware {
+            file_open: true,
+            corrupt_line: Some(3),
+            ..Default::default()
+        }));
+        let path = start_fake_firmware(firmware.clone());
+        let service = MarlinService::new(path, 115200);
+
+        // line 3 is the first M27 C, which has to be sent again
+        let state = service.printer_state("key").await.unwrap();
+        assert!(state.state.flags.ready, "{:?}", state);
+        assert_eq!(state.temperature.tool0.actual, 209.8);
+        assert_eq!(state.temperature.bed.target, 60.);
+
+        service.start_job("key").await.unwrap();
+        let job = service.job_state("key").await.unwrap();
+        assert_eq!(job.progress.completion, Some(25.));
+        assert_eq!(job.job.file.name.as_deref(), Some("benchy.gcode"));
+
+        let err = service
+            .printhead_command("key", PrinterMove::home_all())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::Conflict409(_)
+        ));
+
+        service.pause_job("key", PauseAction::Toggle).await.unwrap();
+        let state = service.printer_state("key").await.unwrap();
+        assert!(state.state.flags.paused);
+        // like a filament change mid-print
+        service.save_position("key").await.unwrap();
+        let lift = PrinterMove::Move {
+            x: None,
+            y: None,
+            z: Some(10.),
+            absolute: Some(false),
+            speed: None,
+        };
+        service.printhead_command("key", lift).await.unwrap();
+        let unload = Tool::Extrude {
+            amount: -450.,
+            speed: None,
+        };
+        service.tool_command("key", unload).await.unwrap();
+        service.restore_position("key").await.unwrap();
+        firmware.lock().unwrap().no_g60 = true;
+        let err = service.save_position("key").await.unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::NotImplemented501(_)
+        ));
+        service.cancel_job("key").await.unwrap();
+
+        service
+            .printhead_command("key", PrinterMove::home_all())
+            .await
+            .unwrap();
+
+        let firmware = firmware.lock().unwrap();
+        assert_eq!(firmware.corrupt_line, None);
+        let commands = &firmware.commands;
+        assert_eq!(&commands[..4], ["M105", "M27", "M27 C", "M31"]);
+        assert!(commands.contains(&"M25".to_string()));
+        assert!(commands.contains(&"G1 Z10".to_string()));
+        assert!(commands.contains(&"G1 E-450".to_string()));
+        let restored = commands.iter().position(|c| c == "G61 S0 X Y").unwrap();
+        assert_eq!(commands[restored + 1], "G61 S0 Z");
+        assert!(commands.contains(&"M524".to_string()));
+        assert_eq!(commands.last().unwrap(), "G28 X Y Z");
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        let service = MarlinService::new("/dev/does-not-exist", 115200);
+        assert!(service.printer_state("key").await.is_err());
+        assert!(service.job_state("key").await.is_err());
+    }
+}
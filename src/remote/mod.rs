@@ -1,3 +1,8 @@
 mod error_util;
+pub mod gcode;
+pub mod marlin_service;
+pub mod moonraker_service;
 pub mod notify_homebridge;
+pub mod notify_webhook;
 pub mod printer_service;
+pub mod prusalink_service;
@@ -0,0 +1,604 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::anyhow;
+use reqwest::{Client, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::error_util::LogInvalidJson;
+use super::gcode::{printhead_gcode, tool_gcode};
+use crate::data_defs::printer_job_action::PauseAction;
+use crate::data_defs::printer_job_state::{self, JobState};
+use crate::data_defs::printer_move::PrinterMove;
+use crate::data_defs::printer_state::{self, PrinterState};
+use crate::data_defs::printer_tool::Tool;
+use crate::traits::printer_trait::{Capabilities, Printer};
+use crate::utils::http_errors::AnyhowHTTPError;
+
+/// Klipper only reports the filament length, so the volume assumes the usual diameter
+const FILAMENT_DIAMETER: f64 = 1.75;
+
+/// Talks to Klipper through Moonraker's JSON-RPC api (`/server/jsonrpc`).
+///
+/// Klipper has no selected file like OctoPrint, so starting a job
+/// prints the last file again, and a paused print can't be restarted.
+pub struct MoonrakerService {
+    client: reqwest::Client,
+    /// Moonraker root without a trailing slash, e.g. `http://klipper.local`
+    base_url: String,
+    next_id: AtomicU64,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueryResult {
+    status: KlipperStatus,
+}
+
+/// The parts of the Klipper objects we query
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct KlipperStatus {
+    pub webhooks: Webhooks,
+    pub print_stats: PrintStats,
+    pub virtual_sdcard: VirtualSdcard,
+    pub extruder: Heater,
+    pub heater_bed: Heater,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Webhooks {
+    /// `ready`, `startup`, `shutdown` or `error`
+    pub state: String,
+    pub state_message: String,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PrintStats {
+    pub filename: String,
+    /// in seconds, without pauses
+    pub print_duration: f64,
+    /// `standby`, `printing`, `paused`, `complete`, `cancelled` or `error`
+    pub state: String,
+    pub message: String,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct VirtualSdcard {
+    /// 0 to 1
+    pub progress: f64,
+    pub file_position: i64,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Heater {
+    pub temperature: f64,
+    pub target: f64,
+}
+
+/// From `server.files.metadata`, written by the slicer
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FileMetadata {
+    pub estimated_time: Option<f64>,
+    /// in mm
+    pub filament_total: Option<f64>,
+    pub size: Option<i64>,
+    pub modified: Option<f64>,
+}
+
+impl MoonrakerService {
+    pub fn new(client: Client, base_url: &Url) -> Self {
+        Self {
+            client,
+            base_url: base_url.as_str().trim_end_matches('/').to_string(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Moonraker only wants a key if it's set up to require one
+    async fn call<T>(&self, api_key: &str, method: &str, params: Value) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let mut request = self
+            .client
+            .post(format!("{}/server/jsonrpc", self.base_url))
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": params,
+                "id": self.next_id.fetch_add(1, Ordering::Relaxed),
+            }));
+        if !api_key.is_empty() {
+            request = request.header("X-Api-Key", api_key);
+        }
+
+        let response: RpcResponse<T> = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json_log_if_invalid()
+            .await?;
+
+        match (response.result, response.error) {
+            (_, Some(RpcError { code: 400, message })) => {
+                // Klipper uses 400 for commands that don't fit the printer's state
+                Err(conflict(&message))
+            }
+            (_, Some(RpcError { code, message })) => {
+                Err(anyhow!("{} ({}): {}", method, code, message))
+            }
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(anyhow!("{}: empty response", method)),
+        }
+    }
+
+    async fn status(&self, api_key: &str) -> anyhow::Result<KlipperStatus> {
+        let result: QueryResult = self
+            .call(
+                api_key,
+                "printer.objects.query",
+                json!({
+                    "objects": {
+                        "webhooks": ["state", "state_message"],
+                        "print_stats": ["filename", "print_duration", "state", "message"],
+                        "virtual_sdcard": ["progress", "file_position"],
+                        "extruder": ["temperature", "target"],
+                        "heater_bed": ["temperature", "target"],
+                    }
+                }),
+            )
+            .await?;
+        Ok(result.status)
+    }
+
+    async fn metadata(&self, api_key: &str, filename: &str) -> anyhow::Result<FileMetadata> {
+        self.call(
+            api_key,
+            "server.files.metadata",
+            json!({ "filename": filename }),
+        )
+        .await
+    }
+
+    async fn gcode(&self, api_key: &str, script: &str) -> anyhow::Result<()> {
+        let _: Value = self
+            .call(api_key, "printer.gcode.script", json!({ "script": script }))
+            .await?;
+        Ok(())
+    }
+
+    async fn print_command(
+        &self,
+        api_key: &str,
+        method: &str,
+        params: Value,
+    ) -> anyhow::Result<()> {
+        let _: Value = self.call(api_key, method, params).await?;
+        Ok(())
+    }
+
+    /// Moving or extruding during a print would ruin it.
+    /// While it's paused is fine, that's how filament gets changed mid-print.
+    async fn ensure_not_printing(&self, api_key: &str) -> anyhow::Result<()> {
+        let status = self.status(api_key).await?;
+        if status.print_stats.state == "printing" {
+            return Err(conflict("Printer is currently printing"));
+        }
+        Ok(())
+    }
+}
+
+fn conflict(message: &str) -> anyhow::Error {
+    AnyhowHTTPError::Conflict409(message.to_string()).into()
+}
+
+pub fn printer_state_from(status: &KlipperStatus) -> PrinterState {
+    let klippy_ready = status.webhooks.state == "ready";
+    let klippy_error = matches!(status.webhooks.state.as_str(), "shutdown" | "error");
+    let print_state = status.print_stats.state.as_str();
+    let print_error = print_state == "error";
+
+    let error = if klippy_error {
+        status.webhooks.state_message.trim().to_string()
+    } else if print_error {
+        status.print_stats.message.clone()
+    } else {
+        String::new()
+    };
+
+    let operational = klippy_ready && !print_error;
+    let printing = klippy_ready && print_state == "printing";
+    let paused = klippy_ready && print_state == "paused";
+
+    PrinterState {
+        sd: printer_state::Sd { ready: false },
+        state: printer_state::State {
+            error,
+            flags: printer_state::Flags {
+                cancelling: false,
+                closed_or_error: !klippy_ready || print_error,
+                error: klippy_error || print_error,
+                finishing: false,
+                operational,
+                paused,
+                pausing: false,
+                printing,
+                ready: operational && !printing && !paused,
+                resuming: false,
+                sd_ready: false,
+            },
+            text: state_text(status).to_string(),
+        },
+        temperature: printer_state::Temperature {
+            bed: printer_state::Bed {
+                actual: status.heater_bed.temperature,
+                offset: 0,
+                target: status.heater_bed.target,
+            },
+            tool0: printer_state::Tool0 {
+                actual: status.extruder.temperature,
+                offset: 0,
+                target: status.extruder.target,
+            },
+        },
+    }
+}
+
+/// Named like OctoPrint's states, as that is what the rest of the code expects
+fn state_text(status: &KlipperStatus) -> &'static str {
+    match (
+        status.webhooks.state.as_str(),
+        status.print_stats.state.as_str(),
+    ) {
+        ("ready", "printing") => "Printing",
+        ("ready", "paused") => "Paused",
+        ("ready", "error") => "Error",
+        ("ready", _) => "Operational",
+        ("startup", _) => "Connecting",
+        _ => "Offline after error",
+    }
+}
+
+pub fn job_state_from(status: &KlipperStatus, metadata: Option<&FileMetadata>) -> JobState {
+    let print_stats = &status.print_stats;
+    let has_file = !print_stats.filename.is_empty();
+    let estimated = metadata.and_then(|m| m.estimated_time);
+    let active = matches!(print_stats.state.as_str(), "printing" | "paused");
+
+    let completion = match print_stats.state.as_str() {
+        "complete" => Some(100.),
+        _ if has_file => Some(status.virtual_sdcard.progress * 100.),
+        _ => None,
+    };
+
+    JobState {
+        job: printer_job_state::Job {
+            file: printer_job_state::File {
+                name: has_file.then(|| print_stats.filename.clone()),
+                origin: has_file.then(|| "local".to_string()),
+                size: metadata.and_then(|m| m.size),
+                date: metadata.and_then(|m| m.modified).map(|t| t as i64),
+            },
+            estimated_print_time: estimated,
+            average_print_time: None,
+            filament: metadata.and_then(|m| m.filament_total).map(|length| {
+                let radius = FILAMENT_DIAMETER / 2.;
+                printer_job_state::Filament {
+                    tool0: Some(printer_job_state::Tool0 {
+                        length,
+                        volume: std::f64::consts::PI * radius * radius * length / 1000.,
+                    }),
+                }
+            }),
+            last_print_time: None,
+            user: None,
+        },
+        progress: printer_job_state::Progress {
+            completion,
+            filepos: has_file.then_some(status.virtual_sdcard.file_position),
+            print_time: has_file.then(|| print_stats.print_duration.round() as i64),
+            print_time_left: match (active, estimated) {
+                (true, Some(estimated)) => {
+                    Some((estimated - print_stats.print_duration).max(0.).round() as i64)
+                }
+                _ => None,
+            },
+        },
+        state: state_text(status).to_string(),
+        error: (print_stats.state == "error").then(|| print_stats.message.clone()),
+    }
+}
+
+#[async_trait::async_trait]
+impl Printer for MoonrakerService {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            pause: true,
+            restart: false,
+            raw_extrusion: true,
+            bed_heater: true,
+            multiple_tools: false,
+            webcam: true,
+            file_upload: true,
+        }
+    }
+
+    async fn printer_state(&self, api_key: &str) -> anyhow::Result<PrinterState> {
+        Ok(printer_state_from(&self.status(api_key).await?))
+    }
+
+    async fn job_state(&self, api_key: &str) -> anyhow::Result<JobState> {
+        let status = self.status(api_key).await?;
+        let filename = &status.print_stats.filename;
+        // the slicer estimates are nice to have, but not worth failing over
+        let metadata = match filename.is_empty() {
+            true => None,
+            false => self.metadata(api_key, filename).await.ok(),
+        };
+        Ok(job_state_from(&status, metadata.as_ref()))
+    }
+
+    async fn tool_command(&self, api_key: &str, command: Tool) -> anyhow::Result<()> {
+        if let Tool::Extrude { .. } = command {
+            self.ensure_not_printing(api_key).await?;
+        }
+        self.gcode(api_key, &tool_gcode(&command)).await
+    }
+
+    async fn printhead_command(&self, api_key: &str, command: PrinterMove) -> anyhow::Result<()> {
+        self.ensure_not_printing(api_key).await?;
+        self.gcode(api_key, &printhead_gcode(&command)).await
+    }
+
+    async fn save_position(&self, api_key: &str) -> anyhow::Result<()> {
+        self.ensure_not_printing(api_key).await?;
+        self.gcode(api_key, "SAVE_GCODE_STATE NAME=filament_change")
+            .await
+    }
+
+    /// Klipper moves all axes back at once. The print head comes down while it's on its way,
+    /// so it's never lower than the print's top layer until it's back where it was.
+    async fn restore_position(&self, api_key: &str) -> anyhow::Result<()> {
+        self.ensure_not_printing(api_key).await?;
+        self.gcode(api_key, "RESTORE_GCODE_STATE NAME=filament_change MOVE=1")
+            .await
+    }
+
+    async fn quick_stop(&self, _api_key: &str) -> anyhow::Result<()> {
+        // Klipper has no M410, only M112 which shuts the printer down
+        Err(AnyhowHTTPError::NotImplemented501(
+            "Klipper can't stop a move without an emergency stop".to_string(),
+        )
+        .into())
+    }
+
+    async fn cancel_job(&self, api_key: &str) -> anyhow::Result<()> {
+        let status = self.status(api_key).await?;
+        if !matches!(status.print_stats.state.as_str(), "printing" | "paused") {
+            return Err(conflict("Printer is neither printing nor paused"));
+        }
+        self.print_command(api_key, "printer.print.cancel", json!({}))
+            .await
+    }
+
+    async fn start_job(&self, api_key: &str) -> anyhow::Result<()> {
+        let status = self.status(api_key).await?;
+        if matches!(status.print_stats.state.as_str(), "printing" | "paused") {
+            return Err(conflict("Printer is busy"));
+        }
+        if status.print_stats.filename.is_empty() {
+            return Err(conflict("No file selected"));
+        }
+        self.print_command(
+            api_key,
+            "printer.print.start",
+            json!({ "filename": status.print_stats.filename }),
+        )
+        .await
+    }
+
+    async fn restart_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        Err(
+            AnyhowHTTPError::NotImplemented501("Klipper can't restart a paused print".to_string())
+                .into(),
+        )
+    }
+
+    async fn pause_job(&self, api_key: &str, action: PauseAction) -> anyhow::Result<()> {
+        let status = self.status(api_key).await?;
+        let method = match (action, status.print_stats.state.as_str()) {
+            (PauseAction::Pause | PauseAction::Toggle, "printing") => "printer.print.pause",
+            (PauseAction::Resume | PauseAction::Toggle, "paused") => "printer.print.resume",
+            _ => return Err(conflict("Printer is neither printing nor paused")),
+        };
+        self.print_command(api_key, method, json!({})).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    fn printing() -> KlipperStatus {
+        KlipperStatus {
+            webhooks: Webhooks {
+                state: "ready".to_string(),
+                state_message: "Printer is ready".to_string(),
+            },
+            print_stats: PrintStats {
+                filename: "benchy.gcode".to_string(),
+                print_duration: 900.,
+                state: "printing".to_string(),
+                message: String::new(),
+            },
+            virtual_sdcard: VirtualSdcard {
+                progress: 0.25,
+                file_position: 1000,
+            },
+            extruder: Heater {
+                temperature: 209.8,
+                target: 210.,
+            },
+            heater_bed: Heater {
+                temperature: 60.,
+                target: 60.,
+            },
+        }
+    }
+
+    #[test]
+    fn test_state_mapping() {
+        let state = printer_state_from(&printing());
+        assert!(state.state.flags.printing && state.state.flags.operational);
+        assert!(!state.state.flags.ready);
+        assert_eq!(state.temperature.tool0.actual, 209.8);
+
+        let metadata = FileMetadata {
+            estimated_time: Some(3600.),
+            filament_total: Some(1000.),
+            ..Default::default()
+        };
+        let job = job_state_from(&printing(), Some(&metadata));
+        assert_eq!(job.job.file.name.as_deref(), Some("benchy.gcode"));
+        assert_eq!(job.progress.completion, Some(25.));
+        assert_eq!(job.progress.print_time_left, Some(2700));
+        let volume = job.job.filament.unwrap().tool0.unwrap().volume;
+        assert!((volume - 2.405).abs() < 0.001, "{}", volume);
+
+        let mut shutdown = printing();
+        shutdown.webhooks.state = "shutdown".to_string();
+        shutdown.webhooks.state_message = "MCU 'mcu' shutdown: Thermal runaway\n".to_string();
+        let state = printer_state_from(&shutdown);
+        assert!(state.state.flags.error && !state.state.flags.operational);
+        assert_eq!(state.state.error, "MCU 'mcu' shutdown: Thermal runaway");
+
+        let mut standby = printing();
+        standby.print_stats = PrintStats {
+            state: "standby".to_string(),
+            ..Default::default()
+        };
+        let job = job_state_from(&standby, None);
+        assert_eq!(job.progress.completion, None);
+        assert_eq!(job.state, "Operational");
+    }
+
+    #[actix_web::test]
+    async fn test_json_rpc() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_clone = calls.clone();
+        let print_state = Arc::new(Mutex::new("printing"));
+        let print_state_clone = print_state.clone();
+
+        // answers like Moonraker with a print running, or paused, and a cold hot end
+        let server = HttpServer::new(move || {
+            let calls = calls_clone.clone();
+            let print_state = print_state_clone.clone();
+            App::new().route(
+                "/server/jsonrpc",
+                web::post().to(move |body: web::Json<Value>| {
+                    let calls = calls.clone();
+                    let print_state = *print_state.lock().unwrap();
+                    async move {
+                        let method = body["method"].as_str().unwrap().to_string();
+                        calls.lock().unwrap().push(method.clone());
+                        let mut response = match method.as_str() {
+                            "printer.objects.query" => json!({
+                                "result": {
+                                    "eventtime": 1.0,
+                                    "status": {
+                                        "webhooks": {"state": "ready"},
+                                        "print_stats": {"state": print_state, "filename": "benchy.gcode"},
+                                    }
+                                }
+                            }),
+                            "printer.gcode.script"
+                                if body["params"]["script"].as_str().unwrap().contains(" E") =>
+                            {
+                                json!({
+                                    "error": {"code": 400, "message": "Extrude below minimum temp"}
+                                })
+                            }
+                            _ => json!({"result": "ok"}),
+                        };
+                        response["jsonrpc"] = json!("2.0");
+                        response["id"] = body["id"].clone();
+                        HttpResponse::Ok().json(response)
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        tokio::spawn(server.run());
+
+        let url = Url::parse(&format!("http://{}", addr)).unwrap();
+        let service = MoonrakerService::new(Client::new(), &url);
+
+        service.cancel_job("").await.unwrap();
+        let err = service
+            .printhead_command("", PrinterMove::home_all())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::Conflict409(_)
+        ));
+
+        // like a filament change mid-print
+        *print_state.lock().unwrap() = "paused";
+        let lift = PrinterMove::Move {
+            x: None,
+            y: None,
+            z: Some(10.),
+            absolute: Some(false),
+            speed: None,
+        };
+        service.printhead_command("", lift).await.unwrap();
+        let unload = Tool::Extrude {
+            amount: -450.,
+            speed: None,
+        };
+        let err = service.tool_command("", unload).await.unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::Conflict409(m) if m == "Extrude below minimum temp"
+        ));
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "printer.objects.query",
+                "printer.print.cancel",
+                "printer.objects.query",
+                "printer.objects.query",
+                "printer.gcode.script",
+                "printer.objects.query",
+                "printer.gcode.script"
+            ]
+        );
+    }
+}
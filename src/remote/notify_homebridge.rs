@@ -1,7 +1,8 @@
 use anyhow::ensure;
-use reqwest::Client;
+use reqwest::{Client, Url};
 use serde::Deserialize;
 
+use crate::events::PrinterEvent;
 use crate::traits::notify_trait::Notifier;
 
 #[derive(Deserialize, Debug)]
@@ -10,15 +11,17 @@ pub struct NotifyResponse {
 }
 
 /// this pings the homebridge plugin https://www.npmjs.com/package/homebridge-http-doorbell-v3
+/// A doorbell can't say anything, so it only rings when a print finishes or fails,
+/// or a filament change is done. Cancelled prints don't ring, since someone cancelled them on purpose.
 pub struct NotifyHomebridge {
     pub url: String,
     pub web_client: reqwest::Client,
 }
 
 impl NotifyHomebridge {
-    pub fn new(web_client: Client) -> Self {
+    pub fn new(web_client: Client, url: &Url) -> Self {
         Self {
-            url: "http://192.168.1.240:9091/printjob".to_string(),
+            url: url.to_string(),
             web_client,
         }
     }
@@ -26,7 +29,16 @@ impl NotifyHomebridge {
 
 #[async_trait::async_trait]
 impl Notifier for NotifyHomebridge {
-    async fn notify(&self) -> anyhow::Result<()> {
+    async fn notify(&self, _printer_id: &str, event: &PrinterEvent) -> anyhow::Result<()> {
+        if !matches!(
+            event,
+            PrinterEvent::PrintFinished(_)
+                | PrinterEvent::PrintFailed { .. }
+                | PrinterEvent::FilamentActionFinished { .. }
+        ) {
+            return Ok(());
+        }
+
         let resp: NotifyResponse = self
             .web_client
             .get(&self.url)
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use reqwest::Client;
+use serde_json::Value;
+
+use crate::config::WebhookConfig;
+use crate::events::PrinterEvent;
+use crate::traits::notify_trait::Notifier;
+
+/// Sends events to any HTTP endpoint (ntfy, Home Assistant webhooks, chat bots, ...).
+///
+/// The body template is JSON (or a plain string) in which these placeholders are filled in:
+/// `{{printer}}`, `{{event}}`, `{{outcome}}`, `{{message}}`, `{{file_name}}`, `{{duration}}`
+/// (seconds), `{{duration_text}}`, `{{filament_length}}` (mm), `{{filament_volume}}` (cm³)
+/// and `{{error}}`.
+///
+/// A string that is nothing but a placeholder is replaced by the value itself,
+/// so `"{{duration}}"` becomes a number (or `null`). Anywhere else the value is inserted as text.
+pub struct NotifyWebhook {
+    pub config: WebhookConfig,
+    pub web_client: reqwest::Client,
+}
+
+impl NotifyWebhook {
+    pub fn new(web_client: Client, config: WebhookConfig) -> Self {
+        Self { config, web_client }
+    }
+}
+
+#[async_trait::async_trait]
+impl Notifier for NotifyWebhook {
+    async fn notify(&self, printer_id: &str, event: &PrinterEvent) -> anyhow::Result<()> {
+        let request = self
+            .web_client
+            .request(self.config.method.clone(), self.config.url.clone())
+            .headers(self.config.headers.clone());
+
+        let request = match &self.config.body {
+            None => request.json(&serde_json::json!({
+                "printer": printer_id,
+                "message": event.describe(),
+                "data": event,
+            })),
+            Some(template) => match render(template, &placeholders(printer_id, event)) {
+                Value::String(text) => request.body(text),
+                json => request.json(&json),
+            },
+        };
+
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn placeholders(printer_id: &str, event: &PrinterEvent) -> BTreeMap<&'static str, Value> {
+    let print = event.print();
+    let filament = print.and_then(|print| print.filament.as_ref());
+    let event_name = serde_json::to_value(event.kind()).unwrap_or(Value::Null);
+
+    let outcome = match event {
+        PrinterEvent::PrintFinished(_) => Value::from("finished"),
+        PrinterEvent::PrintCancelled(_) => Value::from("cancelled"),
+        PrinterEvent::PrintFailed { .. } => Value::from("failed"),
+        _ => Value::Null,
+    };
+
+    let error = match event {
+        PrinterEvent::PrintFailed { error, .. } => error.clone(),
+        PrinterEvent::FilamentActionFinished { error, .. } => error.clone(),
+        PrinterEvent::PrinterDisconnected { error } => Some(error.clone()),
+        _ => None,
+    };
+
+    BTreeMap::from([
+        ("printer", Value::from(printer_id)),
+        ("event", event_name),
+        ("outcome", outcome),
+        ("message", Value::from(event.describe())),
+        (
+            "file_name",
+            print
+                .and_then(|print| print.file_name.clone())
+                .map_or(Value::Null, Value::from),
+        ),
+        (
+            "duration",
+            print
+                .and_then(|print| print.duration)
+                .map_or(Value::Null, Value::from),
+        ),
+        (
+            "duration_text",
+            print
+                .and_then(|print| print.duration_human_readable())
+                .map_or(Value::Null, Value::from),
+        ),
+        (
+            "filament_length",
+            filament.map_or(Value::Null, |f| Value::from(f.length)),
+        ),
+        (
+            "filament_volume",
+            filament.map_or(Value::Null, |f| Value::from(f.volume)),
+        ),
+        ("error", error.map_or(Value::Null, Value::from)),
+    ])
+}
+
+fn render(template: &Value, placeholders: &BTreeMap<&'static str, Value>) -> Value {
+    match template {
+        Value::String(s) => render_string(s, placeholders),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render(item, placeholders))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render(v, placeholders)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn render_string(s: &str, placeholders: &BTreeMap<&'static str, Value>) -> Value {
+    // the whole string is a placeholder, so keep the value's type
+    if let Some(value) = s
+        .strip_prefix("{{")
+        .and_then(|rest| rest.strip_suffix("}}"))
+        .and_then(|name| placeholders.get(name.trim()))
+    {
+        return value.clone();
+    }
+
+    // in a single pass, so that a value containing a placeholder,
+    // like a file named "{{outcome}}.gcode", isn't expanded again
+    let mut rendered = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let end = start + 2 + len + 2;
+        rendered.push_str(&rest[..start]);
+        match placeholders.get(name.trim()) {
+            Some(Value::Null) => {}
+            Some(Value::String(text)) => rendered.push_str(text),
+            Some(other) => rendered.push_str(&other.to_string()),
+            // unknown placeholders are left alone
+            None => rendered.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    rendered.push_str(rest);
+    Value::String(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use reqwest::header::HeaderMap;
+    use reqwest::{Method, Url};
+    use serde_json::json;
+
+    use super::*;
+    use crate::data_defs::printer_job_state::Tool0;
+    use crate::events::PrintInfo;
+
+    fn finished() -> PrinterEvent {
+        PrinterEvent::PrintFinished(PrintInfo {
+            file_name: Some("benchy.gcode".to_string()),
+            duration: Some(3600 + 60),
+            filament: Some(Tool0 {
+                length: 1500.,
+                volume: 3.6,
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_render() {
+        let template = json!({
+            "title": "{{printer}}: {{outcome}}",
+            "seconds": "{{duration}}",
+            "tags": ["{{event}}", "{{error}}"],
+            "length": "{{filament_length}} mm",
+            "spaced": "Done: {{ printer }}",
+            "fixed": 5,
+        });
+        let rendered = render(&template, &placeholders("ender", &finished()));
+        assert_eq!(
+            rendered,
+            json!({
+                "title": "ender: finished",
+                "seconds": 3660,
+                "tags": ["printFinished", null],
+                "length": "1500.0 mm",
+                "spaced": "Done: ender",
+                "fixed": 5,
+            })
+        );
+
+        let mut sneaky = finished();
+        if let PrinterEvent::PrintFinished(print) = &mut sneaky {
+            print.file_name = Some("{{outcome}}.gcode".to_string());
+        }
+        let rendered = render(
+            &json!("{{file_name}} is {{outcome}}, {{unknown}} {{"),
+            &placeholders("ender", &sneaky),
+        );
+        assert_eq!(
+            rendered,
+            json!("{{outcome}}.gcode is finished, {{unknown}} {{")
+        );
+
+        let rendered = render(&json!("{{message}}"), &placeholders("ender", &finished()));
+        assert_eq!(
+            rendered,
+            json!("Finished printing benchy.gcode. Printing took 1 hour and 1 minute")
+        );
+    }
+
+    /// What the stand-in server received
+    #[derive(Debug)]
+    struct Received {
+        method: String,
+        title: Option<String>,
+        body: String,
+    }
+
+    async fn stand_in_server() -> (Url, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = received.clone();
+
+        let server = HttpServer::new(move || {
+            let received = received_clone.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+                let received = received.clone();
+                async move {
+                    received.lock().unwrap().push(Received {
+                        method: req.method().to_string(),
+                        title: req
+                            .headers()
+                            .get("Title")
+                            .map(|v| v.to_str().unwrap().to_string()),
+                        body,
+                    });
+                    HttpResponse::Ok().finish()
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+        let addr = server.addrs()[0];
+        tokio::spawn(server.run());
+
+        let url = Url::parse(&format!("http://{}/hook", addr)).unwrap();
+        (url, received)
+    }
+
+    #[actix_web::test]
+    async fn test_notify_webhook() {
+        let (url, received) = stand_in_server().await;
+
+        let mut headers = HeaderMap::new();
+        headers.append("Title", "3D Printer".parse().unwrap());
+        let notifier = NotifyWebhook::new(
+            Client::new(),
+            WebhookConfig {
+                name: "test".to_string(),
+                method: Method::PUT,
+                url,
+                headers,
+                body: Some(json!({"file": "{{file_name}}", "outcome": "{{outcome}}"})),
+            },
+        );
+
+        notifier.notify("ender", &finished()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].method, "PUT");
+        assert_eq!(received[0].title.as_deref(), Some("3D Printer"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&received[0].body).unwrap(),
+            json!({"file": "benchy.gcode", "outcome": "finished"})
+        );
+    }
+}
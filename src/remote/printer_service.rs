@@ -1,16 +1,19 @@
 #![allow(dead_code)]
 
-use anyhow::ensure;
 use log::debug;
 use reqwest::header::{self, HeaderMap};
-use reqwest::Client;
+use reqwest::{Client, Url};
+use serde_json::json;
 
 use super::error_util::LogInvalidJson;
-use crate::data_defs::printer_job_action::JobAction;
+use super::gcode::{RESTORE_POSITION, SAVE_POSITION};
+use crate::data_defs::printer_job_action::{JobAction, PauseAction};
 use crate::data_defs::printer_move::PrinterMove;
-use crate::data_defs::printer_tool::{Targets, Tool};
-use crate::filaments::{Filament, HotEndTemperature};
-use crate::{data_defs::printer_state::PrinterState, traits::printer_trait::Printer};
+use crate::data_defs::printer_tool::Tool;
+use crate::{
+    data_defs::printer_state::PrinterState,
+    traits::printer_trait::{Capabilities, Printer},
+};
 
 fn get_default_headers(api_key: &str) -> HeaderMap {
     let mut h = header::HeaderMap::new();
@@ -20,19 +23,22 @@ fn get_default_headers(api_key: &str) -> HeaderMap {
 
 pub struct PrinterService {
     client: reqwest::Client,
+    /// OctoPrint api root without a trailing slash, e.g. `http://192.168.1.113/api`
+    base_url: String,
 }
 
 impl PrinterService {
-    const PREFIX: &'static str = "http://192.168.1.113/api";
-
-    pub fn new(client: Client) -> Self {
-        Self { client }
+    pub fn new(client: Client, base_url: &Url) -> Self {
+        Self {
+            client,
+            base_url: base_url.as_str().trim_end_matches('/').to_string(),
+        }
     }
 
     pub async fn version(&self) -> anyhow::Result<String> {
         let resp = self
             .client
-            .get(format!("{}/version", PrinterService::PREFIX))
+            .get(format!("{}/version", self.base_url))
             .send()
             .await?
             .error_for_status()?
@@ -47,7 +53,7 @@ impl PrinterService {
     {
         let resp = self
             .client
-            .get(format!("{}/{}", Self::PREFIX, endpoint))
+            .get(format!("{}/{}", self.base_url, endpoint))
             .headers(get_default_headers(api_key))
             .send()
             .await?
@@ -68,7 +74,7 @@ impl PrinterService {
     {
         let resp = self
             .client
-            .post(format!("{}/{}", Self::PREFIX, endpoint))
+            .post(format!("{}/{}", self.base_url, endpoint))
             .headers(get_default_headers(api_key))
             .json(&payload)
             .send()
@@ -79,163 +85,80 @@ impl PrinterService {
         debug!("{}", resp);
         Ok(())
     }
+
+    /// Sent as is, one command per line. OctoPrint doesn't pass on the firmware's response.
+    async fn gcode(&self, gcode: &str, api_key: &str) -> anyhow::Result<()> {
+        let commands: Vec<_> = gcode.lines().collect();
+        self.post_no_response("printer/command", json!({ "commands": commands }), api_key)
+            .await
+    }
 }
 
-impl PrinterService {
-    async fn home_all(&self, api_key: &str) -> anyhow::Result<()> {
-        self.post_no_response("printer/printhead", PrinterMove::home_all(), api_key)
-            .await?;
-        Ok(())
+#[async_trait::async_trait]
+impl Printer for PrinterService {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            pause: true,
+            restart: true,
+            raw_extrusion: true,
+            bed_heater: true,
+            multiple_tools: false,
+            webcam: true,
+            file_upload: true,
+        }
     }
 
-    async fn move_print_head_high(&self, api_key: &str) -> anyhow::Result<()> {
-        self.post_no_response(
-            "printer/printhead",
-            PrinterMove::Move {
-                x: None,
-                y: None,
-                z: Some(200.0),
-                absolute: Some(true),
-                speed: None,
-            },
-            api_key,
-        )
-        .await?;
-        Ok(())
+    async fn printer_state(&self, api_key: &str) -> anyhow::Result<PrinterState> {
+        self.get("printer", api_key).await
     }
 
-    async fn hot_end(&self, api_key: &str, temperature: HotEndTemperature) -> anyhow::Result<()> {
-        self.post_no_response(
-            "printer/tool",
-            Tool::Target {
-                targets: Targets {
-                    tool0: temperature.into(),
-                },
-            },
-            api_key,
-        )
-        .await?;
-        Ok(())
+    async fn tool_command(&self, api_key: &str, command: Tool) -> anyhow::Result<()> {
+        self.post_no_response("printer/tool", command, api_key)
+            .await
     }
 
-    async fn _cool_down(&self, api_key: &str) -> anyhow::Result<()> {
-        self.post_no_response(
-            "printer/tool",
-            Tool::Target {
-                targets: Targets { tool0: 0 },
-            },
-            api_key,
-        )
-        .await?;
-        Ok(())
+    async fn printhead_command(&self, api_key: &str, command: PrinterMove) -> anyhow::Result<()> {
+        self.post_no_response("printer/printhead", command, api_key)
+            .await
     }
 
-    /// This will block for a long time (10 min ish)
-    /// waits until hot-end is within 5 degrees of target
-    /// Polls every 10 seconds
-    async fn _wait_for_temperature(
+    async fn job_state(
         &self,
         api_key: &str,
-        target: HotEndTemperature,
-    ) -> anyhow::Result<()> {
-        loop {
-            let state = self.printer_state(api_key).await?;
-            if target.within_5_degrees_of(state.temperature.tool0.actual) {
-                break;
-            }
-
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-        }
-        Ok(())
+    ) -> anyhow::Result<crate::data_defs::printer_job_state::JobState> {
+        self.get("job", api_key).await
     }
 
-    async fn _retract_filament(&self, api_key: &str) -> anyhow::Result<()> {
-        self.post_no_response(
-            "printer/tool",
-            Tool::Extrude {
-                amount: -450.0,
-                speed: Some(250.),
-            },
-            api_key,
-        )
-        .await?;
-
-        Ok(())
+    /// OctoPrint doesn't say where the print head is, so the firmware has to remember it
+    async fn save_position(&self, api_key: &str) -> anyhow::Result<()> {
+        self.gcode(SAVE_POSITION, api_key).await
     }
 
-    async fn _feed_filament(&self, api_key: &str) -> anyhow::Result<()> {
-        // self.post_no_response(
-        //     "printer/tool",
-        //     Tool::Extrude {
-        //         amount: 300.0,
-        //         speed: Some(250.),
-        //     },
-        //     api_key,
-        // )
-        // .await?;
-        self.post_no_response(
-            "printer/tool",
-            Tool::Extrude {
-                amount: 500.0,
-                speed: Some(80.),
-            },
-            api_key,
-        )
-        .await?;
-
-        Ok(())
+    async fn restore_position(&self, api_key: &str) -> anyhow::Result<()> {
+        self.gcode(RESTORE_POSITION, api_key).await
     }
-}
 
-#[async_trait::async_trait]
-impl Printer for PrinterService {
-    async fn printer_state(&self, api_key: &str) -> anyhow::Result<PrinterState> {
-        self.get("printer", api_key).await
+    async fn quick_stop(&self, api_key: &str) -> anyhow::Result<()> {
+        self.gcode("M410", api_key).await
     }
 
-    async fn retract_filament(&self, api_key: &str, filament: Filament) -> anyhow::Result<()> {
-        let state = self.printer_state(api_key).await?;
-        ensure!(state.state.flags.operational, "Printer not operational");
-
-        self.hot_end(api_key, filament.into()).await?;
-        self.home_all(api_key).await?;
-        self.move_print_head_high(api_key).await?;
-
-        self._wait_for_temperature(api_key, filament.into()).await?;
-
-        self._retract_filament(api_key).await
-    }
-
-    async fn feed_filament(&self, api_key: &str, filament: Filament) -> anyhow::Result<()> {
-        let state = self.printer_state(api_key).await?;
-        ensure!(state.state.flags.operational, "Printer not operational");
-
-        self.hot_end(api_key, filament.into()).await?;
-        self.home_all(api_key).await?;
-        self.move_print_head_high(api_key).await?;
-
-        self._wait_for_temperature(api_key, filament.into()).await?;
-
-        self._feed_filament(api_key).await
+    async fn cancel_job(&self, api_key: &str) -> anyhow::Result<()> {
+        self.post_no_response("job", JobAction::Cancel, api_key)
+            .await
     }
 
-    async fn cool_down(&self, api_key: &str) -> anyhow::Result<()> {
-        let state = self.printer_state(api_key).await?;
-        // TODO: replace with something returning 409 (Conflict)
-        ensure!(state.state.flags.operational, "Printer not operational");
-
-        self._cool_down(api_key).await
+    async fn start_job(&self, api_key: &str) -> anyhow::Result<()> {
+        self.post_no_response("job", JobAction::Start, api_key)
+            .await
     }
 
-    async fn job_state(
-        &self,
-        api_key: &str,
-    ) -> anyhow::Result<crate::data_defs::printer_job_state::JobState> {
-        self.get("job", api_key).await
+    async fn restart_job(&self, api_key: &str) -> anyhow::Result<()> {
+        self.post_no_response("job", JobAction::Restart, api_key)
+            .await
     }
 
-    async fn cancel_job(&self, api_key: &str) -> anyhow::Result<()> {
-        self.post_no_response("job", JobAction::Cancel, api_key)
+    async fn pause_job(&self, api_key: &str, action: PauseAction) -> anyhow::Result<()> {
+        self.post_no_response("job", JobAction::Pause { action }, api_key)
             .await
     }
 }
@@ -0,0 +1,493 @@
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use digest_auth::{AuthContext, HttpMethod, WwwAuthenticateHeader};
+use reqwest::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Client, Method, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use super::error_util::LogInvalidJson;
+use crate::data_defs::printer_job_action::PauseAction;
+use crate::data_defs::printer_job_state::{self, JobState};
+use crate::data_defs::printer_move::PrinterMove;
+use crate::data_defs::printer_state::{self, PrinterState};
+use crate::data_defs::printer_tool::Tool;
+use crate::traits::printer_trait::{Capabilities, Printer};
+use crate::utils::http_errors::AnyhowHTTPError;
+
+/// Talks to Prusa printers (MK4, XL, MINI, ...) through PrusaLink's v1 api.
+///
+/// PrusaLink wants digest auth with a password that only this server knows,
+/// so the api key arguments are ignored.
+/// PrusaLink has no way to send G-code, so heating, moving and extruding
+/// (and with them loading filament) aren't supported.
+pub struct PrusaLinkService {
+    client: reqwest::Client,
+    /// PrusaLink root without a trailing slash, e.g. `http://mk4.local`
+    base_url: String,
+    username: String,
+    password: String,
+    /// the last digest challenge, reused until the printer asks again
+    challenge: Mutex<Option<WwwAuthenticateHeader>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub printer: StatusPrinter,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct StatusPrinter {
+    /// `IDLE`, `BUSY`, `PRINTING`, `PAUSED`, `FINISHED`, `STOPPED`, `ERROR`, `ATTENTION` or `READY`
+    pub state: String,
+    pub temp_nozzle: f64,
+    pub target_nozzle: f64,
+    pub temp_bed: f64,
+    pub target_bed: f64,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Job {
+    pub id: u64,
+    pub state: String,
+    /// 0 to 100
+    pub progress: f64,
+    /// in seconds
+    pub time_remaining: Option<i64>,
+    pub time_printing: Option<i64>,
+    pub file: Option<JobFile>,
+}
+
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct JobFile {
+    /// the 8.3 name on the usb stick
+    pub name: String,
+    pub display_name: Option<String>,
+    pub size: Option<i64>,
+    pub m_timestamp: Option<i64>,
+    pub meta: FileMeta,
+}
+
+/// Written by PrusaSlicer
+#[derive(Default, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FileMeta {
+    #[serde(rename = "filament used [mm]")]
+    pub filament_length: Option<f64>,
+    #[serde(rename = "filament used [cm3]")]
+    pub filament_volume: Option<f64>,
+}
+
+fn unsupported(what: &str) -> anyhow::Error {
+    AnyhowHTTPError::NotImplemented501(format!("PrusaLink doesn't support {}", what)).into()
+}
+
+fn nothing_printing() -> anyhow::Error {
+    AnyhowHTTPError::Conflict409("Printer is neither printing nor paused".to_string()).into()
+}
+
+impl PrusaLinkService {
+    pub fn new(
+        client: Client,
+        base_url: &Url,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            base_url: base_url.as_str().trim_end_matches('/').to_string(),
+            username: username.into(),
+            password: password.into(),
+            challenge: Mutex::new(None),
+        }
+    }
+
+    /// Sends the request, answering the digest challenge if there is one
+    async fn send(&self, method: Method, endpoint: &str) -> anyhow::Result<Response> {
+        let url = Url::parse(&format!("{}/api/v1/{}", self.base_url, endpoint))?;
+
+        for attempt in 0..2 {
+            let authorization = {
+                let mut challenge = self.challenge.lock().unwrap();
+                match challenge.as_mut() {
+                    Some(challenge) => {
+                        let context = AuthContext::new_with_method(
+                            self.username.as_str(),
+                            self.password.as_str(),
+                            url.path(),
+                            Option::<&[u8]>::None,
+                            HttpMethod::from(method.as_str().to_string()),
+                        );
+                        Some(challenge.respond(&context)?.to_string())
+                    }
+                    None => None,
+                }
+            };
+
+            let mut request = self.client.request(method.clone(), url.clone());
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            let response = request.send().await?;
+
+            // a missing or stale nonce, so try again with the new challenge
+            if response.status() == StatusCode::UNAUTHORIZED && attempt == 0 {
+                if let Some(header) = response.headers().get(WWW_AUTHENTICATE) {
+                    *self.challenge.lock().unwrap() = Some(digest_auth::parse(header.to_str()?)?);
+                    continue;
+                }
+            }
+            return Ok(response.error_for_status()?);
+        }
+        unreachable!("the second attempt always returns")
+    }
+
+    async fn get<T>(&self, endpoint: &str) -> anyhow::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let response = self.send(Method::GET, endpoint).await?;
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(response.json_log_if_invalid().await?))
+    }
+
+    async fn status(&self) -> anyhow::Result<Status> {
+        self.get("status")
+            .await?
+            .ok_or_else(|| anyhow!("PrusaLink sent an empty status"))
+    }
+
+    /// `None` if there is no job
+    async fn job(&self) -> anyhow::Result<Option<Job>> {
+        self.get("job").await
+    }
+}
+
+pub fn printer_state_from(status: &Status) -> PrinterState {
+    let state = status.printer.state.as_str();
+    let error = state == "ERROR";
+    let operational = !error;
+    let printing = state == "PRINTING";
+    // attention means the print is waiting for someone, e.g. after a filament runout
+    let paused = matches!(state, "PAUSED" | "ATTENTION");
+
+    PrinterState {
+        sd: printer_state::Sd { ready: false },
+        state: printer_state::State {
+            error: match error {
+                true => "Printer reported an error".to_string(),
+                false => String::new(),
+            },
+            flags: printer_state::Flags {
+                cancelling: false,
+                closed_or_error: error,
+                error,
+                finishing: false,
+                operational,
+                paused,
+                pausing: false,
+                printing,
+                ready: operational && !printing && !paused && state != "BUSY",
+                resuming: false,
+                sd_ready: false,
+            },
+            text: state_text(state).to_string(),
+        },
+        temperature: printer_state::Temperature {
+            bed: printer_state::Bed {
+                actual: status.printer.temp_bed,
+                offset: 0,
+                target: status.printer.target_bed,
+            },
+            tool0: printer_state::Tool0 {
+                actual: status.printer.temp_nozzle,
+                offset: 0,
+                target: status.printer.target_nozzle,
+            },
+        },
+    }
+}
+
+/// Named like OctoPrint's states, as that is what the rest of the code expects
+fn state_text(state: &str) -> &'static str {
+    match state {
+        "PRINTING" => "Printing",
+        "PAUSED" => "Paused",
+        "ATTENTION" => "Attention",
+        "BUSY" => "Busy",
+        "ERROR" => "Error",
+        _ => "Operational",
+    }
+}
+
+pub fn job_state_from(job: Option<&Job>) -> JobState {
+    let Some(job) = job else {
+        return JobState {
+            state: "Operational".to_string(),
+            ..Default::default()
+        };
+    };
+    let file = job.file.as_ref();
+    let meta = file.map(|f| &f.meta);
+
+    JobState {
+        job: printer_job_state::Job {
+            file: printer_job_state::File {
+                name: file.map(|f| f.display_name.clone().unwrap_or_else(|| f.name.clone())),
+                origin: file.map(|_| "usb".to_string()),
+                size: file.and_then(|f| f.size),
+                date: file.and_then(|f| f.m_timestamp),
+            },
+            estimated_print_time: job
+                .time_printing
+                .zip(job.time_remaining)
+                .map(|(printing, remaining)| (printing + remaining) as f64),
+            average_print_time: None,
+            filament: meta.and_then(|m| {
+                Some(printer_job_state::Filament {
+                    tool0: Some(printer_job_state::Tool0 {
+                        length: m.filament_length?,
+                        volume: m.filament_volume.unwrap_or_default(),
+                    }),
+                })
+            }),
+            last_print_time: None,
+            user: None,
+        },
+        progress: printer_job_state::Progress {
+            completion: Some(job.progress),
+            filepos: None,
+            print_time: job.time_printing,
+            print_time_left: job.time_remaining,
+        },
+        state: state_text(&job.state).to_string(),
+        error: (job.state == "ERROR").then(|| "Printer reported an error".to_string()),
+    }
+}
+
+#[async_trait::async_trait]
+impl Printer for PrusaLinkService {
+    fn capabilities(&self) -> Capabilities {
+        // no G-code, so no extruding
+        Capabilities {
+            pause: true,
+            restart: false,
+            raw_extrusion: false,
+            bed_heater: true,
+            multiple_tools: false,
+            webcam: false,
+            file_upload: true,
+        }
+    }
+
+    async fn printer_state(&self, _api_key: &str) -> anyhow::Result<PrinterState> {
+        Ok(printer_state_from(&self.status().await?))
+    }
+
+    async fn job_state(&self, _api_key: &str) -> anyhow::Result<JobState> {
+        Ok(job_state_from(self.job().await?.as_ref()))
+    }
+
+    async fn tool_command(&self, _api_key: &str, command: Tool) -> anyhow::Result<()> {
+        Err(unsupported(match command {
+            Tool::Target { .. } => "setting the hot end temperature",
+            Tool::Extrude { .. } => "extruding filament",
+        }))
+    }
+
+    async fn printhead_command(&self, _api_key: &str, _command: PrinterMove) -> anyhow::Result<()> {
+        Err(unsupported("moving the print head"))
+    }
+
+    async fn save_position(&self, _api_key: &str) -> anyhow::Result<()> {
+        Err(unsupported("moving the print head"))
+    }
+
+    async fn restore_position(&self, _api_key: &str) -> anyhow::Result<()> {
+        Err(unsupported("moving the print head"))
+    }
+
+    async fn quick_stop(&self, _api_key: &str) -> anyhow::Result<()> {
+        Err(unsupported("stopping moves"))
+    }
+
+    async fn cancel_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        let job = self.job().await?.ok_or_else(nothing_printing)?;
+        self.send(Method::DELETE, &format!("job/{}", job.id))
+            .await?;
+        Ok(())
+    }
+
+    async fn start_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        Err(unsupported("starting a print without picking a file"))
+    }
+
+    async fn restart_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        Err(unsupported("restarting a print"))
+    }
+
+    async fn pause_job(&self, _api_key: &str, action: PauseAction) -> anyhow::Result<()> {
+        let job = self.job().await?.ok_or_else(nothing_printing)?;
+        let command = match (action, job.state.as_str()) {
+            (PauseAction::Pause | PauseAction::Toggle, "PRINTING") => "pause",
+            (PauseAction::Resume | PauseAction::Toggle, "PAUSED") => "resume",
+            _ => return Err(nothing_printing()),
+        };
+        self.send(Method::PUT, &format!("job/{}/{}", job.id, command))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use digest_auth::AuthorizationHeader;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const PASSWORD: &str = "hunter2";
+
+    fn job_json() -> Value {
+        json!({
+            "id": 297,
+            "state": "PRINTING",
+            "progress": 25.0,
+            "time_remaining": 2700,
+            "time_printing": 900,
+            "file": {
+                "name": "BENCHY~1.BGC",
+                "display_name": "benchy.bgcode",
+                "size": 1234,
+                "meta": {"filament used [mm]": 2000.0, "filament used [cm3]": 4.8}
+            }
+        })
+    }
+
+    #[test]
+    fn test_state_mapping() {
+        let status: Status = serde_json::from_value(json!({
+            "printer": {"state": "ATTENTION", "temp_nozzle": 215.0, "target_nozzle": 215.0}
+        }))
+        .unwrap();
+        let state = printer_state_from(&status);
+        assert!(state.state.flags.paused && !state.state.flags.printing);
+        assert_eq!(state.temperature.tool0.actual, 215.);
+
+        let job: Job = serde_json::from_value(job_json()).unwrap();
+        let job_state = job_state_from(Some(&job));
+        assert_eq!(job_state.job.file.name.as_deref(), Some("benchy.bgcode"));
+        assert_eq!(job_state.job.estimated_print_time, Some(3600.));
+        assert_eq!(job_state.progress.completion, Some(25.));
+        assert_eq!(job_state.job.filament.unwrap().tool0.unwrap().length, 2000.);
+
+        assert_eq!(job_state_from(None).progress.completion, None);
+    }
+
+    /// Whether the request carries a correct digest for the `maker` user
+    fn authorized(req: &HttpRequest) -> bool {
+        let Some(mut answer) = req
+            .headers()
+            .get("Authorization")
+            .and_then(|h| AuthorizationHeader::parse(h.to_str().ok()?).ok())
+        else {
+            return false;
+        };
+
+        let sent = answer.response.clone();
+        let context = AuthContext::new_with_method(
+            "maker",
+            PASSWORD,
+            req.path(),
+            Option::<&[u8]>::None,
+            HttpMethod::from(req.method().as_str().to_string()),
+        );
+        answer.digest(&context);
+        answer.response == sent
+    }
+
+    /// Answers like PrusaLink during a print
+    async fn stand_in_server() -> Url {
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|req: HttpRequest| async move {
+                if !authorized(&req) {
+                    return HttpResponse::Unauthorized()
+                        .insert_header((
+                            "WWW-Authenticate",
+                            r#"Digest realm="Printer API", nonce="abcdef0123456789", qop="auth""#,
+                        ))
+                        .finish();
+                }
+
+                match req.path() {
+                    "/api/v1/status" => {
+                        HttpResponse::Ok().json(json!({"printer": {"state": "PRINTING"}}))
+                    }
+                    "/api/v1/job" => HttpResponse::Ok().json(job_json()),
+                    "/api/v1/job/297/pause" => HttpResponse::NoContent().finish(),
+                    "/api/v1/job/297/resume" => HttpResponse::Conflict().finish(),
+                    _ => HttpResponse::NotFound().finish(),
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+
+        let addr = server.addrs()[0];
+        tokio::spawn(server.run());
+        Url::parse(&format!("http://{}", addr)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_prusalink() {
+        let url = stand_in_server().await;
+        let service = PrusaLinkService::new(Client::new(), &url, "maker", PASSWORD);
+
+        let state = service.printer_state("key").await.unwrap();
+        assert!(state.state.flags.printing);
+        let job = service.job_state("key").await.unwrap();
+        assert_eq!(job.progress.print_time_left, Some(2700));
+
+        service.pause_job("key", PauseAction::Pause).await.unwrap();
+
+        let err = service
+            .pause_job("key", PauseAction::Resume)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::Conflict409(_)
+        ));
+
+        let wrong_password = PrusaLinkService::new(Client::new(), &url, "maker", "nope");
+        let err = wrong_password.printer_state("key").await.unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::AnyHTTPError { code: 401, .. }
+        ));
+
+        let err = service
+            .feed_filament(
+                "key",
+                crate::filaments::FilamentProfiles::default()
+                    .find("PLA")
+                    .unwrap(),
+                &Default::default(),
+                &Default::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            AnyhowHTTPError::from(err),
+            AnyhowHTTPError::NotImplemented501(m) if m == "PrusaLink doesn't support setting the hot end temperature"
+        ));
+    }
+}
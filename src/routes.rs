@@ -0,0 +1,1074 @@
+use std::borrow::BorrowMut;
+use std::future::{ready, Future, Ready};
+use std::sync::Arc;
+
+use actix_web::{delete, get, post, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::auth::{ClientToken, Scope, TokenStore};
+use crate::confirmation::{ConfirmAction, Confirmations};
+use crate::data_defs::printer_job_action::PauseAction;
+use crate::events::{FilamentAction, PrinterEvent};
+use crate::filament_change;
+use crate::filaments::{FilamentProfile, FilamentProfiles};
+use crate::history::PrintHistory;
+use crate::idempotency::{IdempotencyCache, IdempotencyKey};
+use crate::loaded_filament::LoadedFilaments;
+use crate::printer_registry::{PrinterEntry, PrinterRegistry, SelectedPrinter, PRINTER_ID_PARAM};
+use crate::spools::{NewSpool, Spools};
+use crate::traits::notify_trait::Notifier;
+use crate::traits::printer_trait::{Capabilities, Capability};
+use crate::utils::http_errors::AnyhowHTTPError;
+use crate::utils::job_running::{run_job, JobProgress, JobStatus, Progress};
+use crate::utils::logging_util::LoggableResult;
+use crate::utils::time_utils;
+
+const BUILD_TIME: &str = include!(concat!(env!("OUT_DIR"), "/timestamp.txt"));
+
+const NOTHING_PRINTING: &str = "Nothing is currently printing";
+
+const NO_FILAMENT_KNOWN: &str = "No filament has been loaded since the last time it was unloaded";
+
+#[derive(Deserialize, Debug)]
+struct Opts {
+    #[serde(default)]
+    target: Target,
+}
+
+#[derive(Default, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum Target {
+    #[default]
+    Siri,
+    HttpSwitch,
+}
+
+/// if target == HttpSwitch, then it returns 1 for job active, 0 for job inactive
+#[get("/job")]
+async fn job_status(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    info: web::Query<Opts>,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let api_key = printer.api_key.as_str();
+    let job_state = printer.printer.job_state(api_key).await.log_error()?;
+    let percent = job_state.progress.completion.map(|c| c.round() as i32);
+
+    if let Target::HttpSwitch = info.target {
+        return Ok(match percent {
+            // no job
+            None => "0".to_string(),
+            // job done
+            Some(100) => "0".to_string(),
+            // job in progress
+            Some(_) => "1".to_string(),
+        });
+    }
+
+    let time_left = job_state.progress.print_time_left.map(|time| {
+        time_utils::Time::from_seconds(time)
+            .unwrap()
+            .to_human_readable_briefly()
+    });
+
+    let time_taken = job_state.progress.print_time.map(|time| {
+        time_utils::Time::from_seconds(time)
+            .unwrap()
+            .to_human_readable_briefly()
+    });
+
+    Ok(
+        match (percent, time_left, time_taken, job_state.job.file.name) {
+            (Some(100), _, Some(time_taken), Some(file_name)) => {
+                format!(
+                    "Finished printing {}. Printing took {}",
+                    file_name, time_taken
+                )
+            }
+            (Some(100), _, Some(time_taken), None) => {
+                format!("Finished printing. Printing took {}", time_taken)
+            }
+            (Some(100), _, None, Some(file_name)) => {
+                format!(
+                    "Finished printing {}. Printing took an unknown amount of time",
+                    file_name
+                )
+            }
+            (Some(percent), Some(time_left), _, Some(file_name)) => {
+                format!(
+                    "Currently printing {}, which is {}% complete. Printing is expected to finish in {}",
+                    file_name, percent, time_left,
+                )
+            }
+            (Some(percent), Some(time_left), _, None) => {
+                format!(
+                    "Currently printing, which is {}% complete. Printing is expected to finish in {}",
+                    percent, time_left,
+                )
+            }
+            (Some(percent), None, _, Some(file_name)) => {
+                format!(
+                    "Currently printing {}, which is {}% complete",
+                    file_name, percent,
+                )
+            }
+            (Some(percent), None, _, None) => {
+                format!("Currently printing, which is {}% complete", percent)
+            }
+            (None, _, _, _) => NOTHING_PRINTING.to_string(),
+        },
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct ConfirmOpts {
+    /// the token from the first call, for actions that need a confirmation
+    confirm: Option<String>,
+}
+
+/// A 202 with a question for Siri if `action` needs a confirmation that `confirm` isn't.
+/// `None` means go ahead.
+async fn ask_first(
+    printer: &PrinterEntry,
+    token: &ClientToken,
+    confirmations: &Confirmations,
+    action: ConfirmAction,
+    confirm: Option<&str>,
+) -> Result<Option<HttpResponse>, AnyhowHTTPError> {
+    if !confirmations.required(action) {
+        return Ok(None);
+    }
+    if let Some(confirm) = confirm {
+        confirmations.confirm(confirm, &printer.id, action, token)?;
+        return Ok(None);
+    }
+
+    let job_state = printer
+        .printer
+        .job_state(&printer.api_key)
+        .await
+        .log_error()?;
+    let percent = job_state.progress.completion.map(|c| c.round() as i32);
+    let file = job_state
+        .job
+        .file
+        .name
+        .unwrap_or_else(|| "the print".to_string());
+
+    let message = match action {
+        ConfirmAction::Cancel => match percent {
+            // nothing to ask about
+            None => return Err(AnyhowHTTPError::Conflict409(NOTHING_PRINTING.to_string())),
+            Some(percent) => format!("Cancel {} at {}%?", file, percent),
+        },
+        ConfirmAction::Restart => format!("Restart {} from the beginning?", file),
+    };
+
+    let pending = confirmations.request(&printer.id, action, token, message);
+    Ok(Some(HttpResponse::Accepted().json(pending)))
+}
+
+#[delete("/job")]
+async fn cancel_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    confirmations: web::Data<Confirmations>,
+    idempotency: web::Data<IdempotencyCache>,
+    key: IdempotencyKey,
+    info: web::Query<ConfirmOpts>,
+) -> Result<HttpResponse, AnyhowHTTPError> {
+    token.require(Scope::JobControl)?;
+    let api_key = printer.api_key.as_str();
+    let confirm = info.confirm.as_deref();
+
+    idempotency
+        .run(&key, &token, || async {
+            if let Some(question) = ask_first(
+                &printer,
+                &token,
+                &confirmations,
+                ConfirmAction::Cancel,
+                confirm,
+            )
+            .await?
+            {
+                return Ok(question);
+            }
+
+            // the printer will return an error if there is no job to cancel (409)
+            printer
+                .printer
+                .cancel_job(api_key)
+                .await
+                .log_error()
+                .map_err(|e| AnyhowHTTPError::from(e).with_conflict_message(NOTHING_PRINTING))?;
+            Ok(HttpResponse::Ok().body("Cancelling print job"))
+        })
+        .await
+}
+
+#[post("/job/pause")]
+async fn pause_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::JobControl)?;
+    let api_key = printer.api_key.as_str();
+    printer.require(Capability::Pause)?;
+
+    printer
+        .printer
+        .pause_job(api_key, PauseAction::Pause)
+        .await
+        .log_error()
+        .map_err(|e| AnyhowHTTPError::from(e).with_conflict_message(NOTHING_PRINTING))?;
+    Ok("Pausing print job".to_string())
+}
+
+#[post("/job/resume")]
+async fn resume_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::JobControl)?;
+    let api_key = printer.api_key.as_str();
+    printer.require(Capability::Pause)?;
+
+    printer
+        .printer
+        .pause_job(api_key, PauseAction::Resume)
+        .await
+        .log_error()
+        .map_err(|e| {
+            AnyhowHTTPError::from(e).with_conflict_message("Nothing is currently paused")
+        })?;
+    Ok("Resuming print job".to_string())
+}
+
+/// pauses a running job or resumes a paused one, and says which it did
+#[post("/job/toggle-pause")]
+async fn toggle_pause_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::JobControl)?;
+    let api_key = printer.api_key.as_str();
+    printer.require(Capability::Pause)?;
+
+    let state = printer.printer.printer_state(api_key).await.log_error()?;
+    let flags = state.state.flags;
+    if !flags.printing && !flags.paused {
+        return Err(AnyhowHTTPError::Conflict409(NOTHING_PRINTING.to_string()));
+    }
+
+    printer
+        .printer
+        .pause_job(api_key, PauseAction::Toggle)
+        .await
+        .log_error()
+        .map_err(|e| AnyhowHTTPError::from(e).with_conflict_message(NOTHING_PRINTING))?;
+
+    Ok(if flags.paused {
+        "Resuming print job".to_string()
+    } else {
+        "Pausing print job".to_string()
+    })
+}
+
+#[post("/job/restart")]
+async fn restart_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    confirmations: web::Data<Confirmations>,
+    info: web::Query<ConfirmOpts>,
+) -> Result<HttpResponse, AnyhowHTTPError> {
+    token.require(Scope::JobControl)?;
+    let api_key = printer.api_key.as_str();
+    printer.require(Capability::Restart)?;
+    let confirm = info.confirm.as_deref();
+    if let Some(question) = ask_first(
+        &printer,
+        &token,
+        &confirmations,
+        ConfirmAction::Restart,
+        confirm,
+    )
+    .await?
+    {
+        return Ok(question);
+    }
+
+    // OctoPrint only allows restarting a paused job
+    printer
+        .printer
+        .restart_job(api_key)
+        .await
+        .log_error()
+        .map_err(|e| {
+            AnyhowHTTPError::from(e).with_conflict_message(
+                "Only a paused print can be restarted, and nothing is paused",
+            )
+        })?;
+    Ok(HttpResponse::Ok().body("Restarting print job from the beginning"))
+}
+
+#[derive(Deserialize, Debug)]
+struct StartOpts {
+    /// start even if the print needs more than is left on the spool
+    #[serde(default)]
+    force: bool,
+}
+
+#[post("/job/start")]
+async fn start_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    spools: web::Data<Spools>,
+    info: web::Query<StartOpts>,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::JobControl)?;
+    let api_key = printer.api_key.as_str();
+
+    let job_state = printer.printer.job_state(api_key).await.log_error()?;
+
+    let length = job_state
+        .job
+        .filament
+        .as_ref()
+        .and_then(|filament| filament.tool0.as_ref())
+        .map(|tool0| tool0.length);
+    if let (Some(length), Some(spool), false) =
+        (length, spools.mounted(&printer.id).log_error()?, info.force)
+    {
+        let needed = spools.grams(&spool, length);
+        if needed > spool.remaining_grams {
+            return Err(AnyhowHTTPError::Conflict409(format!(
+                "{} needs {} grams of filament, but the {} spool only has {} grams left. \
+                Start it with force to print anyway",
+                job_state.job.file.name.as_deref().unwrap_or("This print"),
+                needed.round(),
+                spool.describe(),
+                spool.remaining_grams.round()
+            )));
+        }
+    }
+
+    printer
+        .printer
+        .start_job(api_key)
+        .await
+        .log_error()
+        .map_err(|e| {
+            AnyhowHTTPError::from(e).with_conflict_message(
+                "Can't start printing. Either no file is selected or the printer is busy",
+            )
+        })?;
+
+    Ok(match job_state.job.file.name {
+        Some(file_name) => format!("Starting to print {}", file_name),
+        None => "Starting print job".to_string(),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct FilamentOpts {
+    /// name or alias of a profile, in any case
+    filament: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RemoveFilamentOpts {
+    /// the loaded one if not given
+    filament: Option<String>,
+}
+
+fn find_profile(
+    profiles: &FilamentProfiles,
+    name: &str,
+) -> Result<FilamentProfile, AnyhowHTTPError> {
+    profiles
+        .find(name)
+        .cloned()
+        .ok_or_else(|| AnyhowHTTPError::NotFound404(format!("No filament profile named {}", name)))
+}
+
+/// The profile named `name`, or else of the loaded filament, or else `fallback`
+fn given_or_loaded(
+    profiles: &FilamentProfiles,
+    loaded: &LoadedFilaments,
+    printer_id: &str,
+    name: Option<&str>,
+    fallback: Option<&FilamentProfile>,
+) -> Result<FilamentProfile, AnyhowHTTPError> {
+    match name {
+        Some(name) => find_profile(profiles, name),
+        None => match (loaded.get(printer_id).log_error()?, fallback) {
+            (Some(loaded), _) => find_profile(profiles, &loaded.filament),
+            (None, Some(fallback)) => Ok(fallback.clone()),
+            (None, None) => Err(AnyhowHTTPError::Conflict409(format!(
+                "{}, so please say which one to remove",
+                NO_FILAMENT_KNOWN
+            ))),
+        },
+    }
+}
+
+/// Which filament is loaded, `null` if it isn't known
+#[get("/filament")]
+async fn loaded_filament(
+    printer: SelectedPrinter,
+    loaded: web::Data<LoadedFilaments>,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    Ok(web::Json(loaded.get(&printer.id).log_error()?))
+}
+
+/// "What filament is loaded", for Siri
+#[get("/filament/summary")]
+async fn loaded_filament_summary(
+    printer: SelectedPrinter,
+    loaded: web::Data<LoadedFilaments>,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    Ok(match loaded.get(&printer.id).log_error()? {
+        Some(filament) => filament.summary(Utc::now()),
+        None => NO_FILAMENT_KNOWN.to_string(),
+    })
+}
+
+#[get("/filaments")]
+async fn list_filaments(
+    profiles: web::Data<FilamentProfiles>,
+    token: ClientToken,
+) -> Result<web::Json<FilamentProfiles>, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    Ok(web::Json(profiles.get_ref().clone()))
+}
+
+/// The app data every filament job route needs
+struct FilamentJobs {
+    notifier: web::Data<dyn Notifier>,
+    idempotency: web::Data<IdempotencyCache>,
+    profiles: web::Data<FilamentProfiles>,
+    loaded: web::Data<LoadedFilaments>,
+}
+
+impl FromRequest for FilamentJobs {
+    type Error = AnyhowHTTPError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(filament_jobs(req))
+    }
+}
+
+fn filament_jobs(req: &HttpRequest) -> Result<FilamentJobs, AnyhowHTTPError> {
+    fn data<T: ?Sized + 'static>(
+        req: &HttpRequest,
+        what: &str,
+    ) -> Result<web::Data<T>, AnyhowHTTPError> {
+        req.app_data::<web::Data<T>>().cloned().ok_or_else(|| {
+            AnyhowHTTPError::InternalServerError500(format!("{} not configured", what))
+        })
+    }
+
+    Ok(FilamentJobs {
+        notifier: data(req, "Notifier")?,
+        idempotency: data(req, "Idempotency cache")?,
+        profiles: data(req, "Filament profiles")?,
+        loaded: data(req, "Loaded filaments")?,
+    })
+}
+
+impl FilamentJobs {
+    /// `spawn`, unless a request with the same `Idempotency-Key` already did
+    async fn start<F, Fut>(
+        &self,
+        printer: SelectedPrinter,
+        token: &ClientToken,
+        key: &IdempotencyKey,
+        action: FilamentAction,
+        filament: String,
+        task: F,
+    ) -> Result<HttpResponse, AnyhowHTTPError>
+    where
+        F: FnOnce(Arc<PrinterEntry>, Progress) -> Fut,
+        Fut: Future<Output = anyhow::Result<String>> + Send + 'static,
+    {
+        self.idempotency
+            .run(key, token, || self.spawn(printer, action, filament, task))
+            .await
+    }
+
+    /// Runs `task` as the printer's long running job. Once it has worked, `filament`
+    /// is what's loaded, or nothing is after a `Retract`. Either way `action` is announced.
+    async fn spawn<F, Fut>(
+        &self,
+        printer: SelectedPrinter,
+        action: FilamentAction,
+        filament: String,
+        task: F,
+    ) -> Result<HttpResponse, AnyhowHTTPError>
+    where
+        F: FnOnce(Arc<PrinterEntry>, Progress) -> Fut,
+        Fut: Future<Output = anyhow::Result<String>> + Send + 'static,
+    {
+        let mut long_running_job = printer.long_running_job.lock().await;
+        let printer_id = printer.id.clone();
+        let notifier = self.notifier.clone();
+        let loaded = self.loaded.clone();
+
+        run_job(
+            |progress| {
+                let task = task(printer.0.clone(), progress);
+                async move {
+                    let result = task.await.log_error();
+                    if result.is_ok() {
+                        match action {
+                            FilamentAction::Retract => loaded.unloaded(&printer_id),
+                            _ => loaded.loaded(&printer_id, &filament, Utc::now()),
+                        }
+                        .log_error()
+                        .ok();
+                    }
+
+                    let event = PrinterEvent::FilamentActionFinished {
+                        action,
+                        filament,
+                        error: result.as_ref().err().map(|e| e.to_string()),
+                    };
+                    notifier.notify(&printer_id, &event).await.log_error().ok();
+
+                    result
+                }
+            },
+            long_running_job.borrow_mut(),
+        )?;
+
+        Ok(HttpResponse::Ok().body("Job started"))
+    }
+}
+
+#[delete("/filament")]
+async fn remove_filament(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    key: IdempotencyKey,
+    jobs: FilamentJobs,
+    info: web::Query<RemoveFilamentOpts>,
+) -> Result<HttpResponse, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    printer.require(Capability::RawExtrusion)?;
+    let filament = given_or_loaded(
+        &jobs.profiles,
+        &jobs.loaded,
+        &printer.id,
+        info.filament.as_deref(),
+        None,
+    )?;
+    let name = filament.name.clone();
+
+    jobs.start(
+        printer,
+        &token,
+        &key,
+        FilamentAction::Retract,
+        name,
+        |printer, progress| async move {
+            printer
+                .printer
+                .retract_filament(&printer.api_key, &filament, &printer.heating, &progress)
+                .await
+                .map(|_| "Finished removing filament".to_string())
+        },
+    )
+    .await
+}
+
+#[post("/filament")]
+async fn feed_filament(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    key: IdempotencyKey,
+    jobs: FilamentJobs,
+    info: web::Query<FilamentOpts>,
+) -> Result<HttpResponse, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    printer.require(Capability::RawExtrusion)?;
+    let filament = find_profile(&jobs.profiles, &info.filament)?;
+    let name = filament.name.clone();
+
+    jobs.start(
+        printer,
+        &token,
+        &key,
+        FilamentAction::Feed,
+        name,
+        |printer, progress| async move {
+            printer
+                .printer
+                .feed_filament(&printer.api_key, &filament, &printer.heating, &progress)
+                .await
+                .map(|_| "Finished feeding filament".to_string())
+        },
+    )
+    .await
+}
+
+#[derive(Deserialize, Debug)]
+struct SwapOpts {
+    /// the new filament
+    filament: String,
+    /// the loaded one if not given
+    from: Option<String>,
+}
+
+/// Unloads and loads in one job, which waits for `POST /filament/continue` in between
+#[post("/filament/swap")]
+async fn swap_filament(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    key: IdempotencyKey,
+    jobs: FilamentJobs,
+    info: web::Query<SwapOpts>,
+) -> Result<HttpResponse, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    printer.require(Capability::RawExtrusion)?;
+    let from = given_or_loaded(
+        &jobs.profiles,
+        &jobs.loaded,
+        &printer.id,
+        info.from.as_deref(),
+        None,
+    )?;
+    let to = find_profile(&jobs.profiles, &info.filament)?;
+    let name = to.name.clone();
+
+    jobs.start(
+        printer,
+        &token,
+        &key,
+        FilamentAction::Swap,
+        name,
+        |printer, progress| async move {
+            printer
+                .printer
+                .swap_filament(&printer.api_key, &from, &to, &printer.heating, &progress)
+                .await
+                .map(|_| format!("Finished swapping {} for {}", from.name, to.name))
+        },
+    )
+    .await
+}
+
+/// Changes filament without stopping the print, and waits for `POST /filament/continue` in between
+#[post("/filament/change")]
+async fn change_filament_mid_print(
+    printer: SelectedPrinter,
+    token: ClientToken,
+    key: IdempotencyKey,
+    jobs: FilamentJobs,
+    info: web::Query<SwapOpts>,
+) -> Result<HttpResponse, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    token.require(Scope::JobControl)?;
+    printer.require(Capability::Pause)?;
+    printer.require(Capability::RawExtrusion)?;
+    let to = find_profile(&jobs.profiles, &info.filament)?;
+    // unloading only needs roughly the right settings, so the new filament's will do
+    let from = given_or_loaded(
+        &jobs.profiles,
+        &jobs.loaded,
+        &printer.id,
+        info.from.as_deref(),
+        Some(&to),
+    )?;
+    let name = to.name.clone();
+
+    // checked behind the `Idempotency-Key`, as once the job has paused
+    // the print a retry would find nothing printing
+    jobs.idempotency
+        .run(&key, &token, || async {
+            let state = printer
+                .printer
+                .printer_state(&printer.api_key)
+                .await
+                .log_error()?;
+            if !state.state.flags.printing {
+                return Err(AnyhowHTTPError::Conflict409(NOTHING_PRINTING.to_string()));
+            }
+
+            jobs.spawn(
+                printer,
+                FilamentAction::Change,
+                name,
+                |printer, progress| async move {
+                    printer
+                        .printer
+                        .change_filament_mid_print(
+                            &printer.api_key,
+                            &from,
+                            &to,
+                            &printer.heating,
+                            &printer.park,
+                            &progress,
+                        )
+                        .await
+                        .map(|_| format!("Changed to {}, the print is going again", to.name))
+                },
+            )
+            .await
+        })
+        .await
+}
+
+/// Tells a swap that the new filament is inserted
+#[post("/filament/continue")]
+async fn continue_filament_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    let long_running_job = printer.long_running_job.lock().await;
+    if !long_running_job.proceed() {
+        return Err(AnyhowHTTPError::Conflict409(
+            "No filament job is waiting for new filament".to_string(),
+        ));
+    }
+    Ok("Loading the new filament".to_string())
+}
+
+/// Stops a filament job and makes sure the printer doesn't stay hot
+#[delete("/filament/job")]
+async fn abort_filament_job(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    let api_key = printer.api_key.as_str();
+
+    let mut long_running_job = printer.long_running_job.lock().await;
+    let Some(stopped) = long_running_job.cancel().await else {
+        return Err(AnyhowHTTPError::Conflict409(
+            "No filament job is running".to_string(),
+        ));
+    };
+
+    filament_change::clean_up(printer.printer.as_ref(), api_key, &stopped)
+        .await
+        .log_error()?;
+
+    Ok("Stopped the filament job and cooling down".to_string())
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ServerInfo {
+    build_time: &'static str,
+    printer: String,
+    job_status: JobStatus,
+    /// only while a job is running
+    progress: Option<JobProgress>,
+}
+
+#[get("/server-info")]
+async fn server_info(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let mut long_running_job = printer.long_running_job.lock().await;
+
+    let progress = long_running_job.current_progress();
+    let status = long_running_job.take_status().await.log_error()?;
+
+    let result = ServerInfo {
+        build_time: BUILD_TIME,
+        printer: printer.id.clone(),
+        job_status: status,
+        progress,
+    };
+
+    Ok(web::Json(result))
+}
+
+/// What the filament job is doing, as a sentence for Siri
+#[get("/filament/status")]
+async fn filament_status(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let mut long_running_job = printer.long_running_job.lock().await;
+
+    let progress = long_running_job.current_progress();
+    Ok(match long_running_job.take_status().await.log_error()? {
+        JobStatus::NoJob => "No filament job is running".to_string(),
+        JobStatus::Running => progress.unwrap_or_default().summary(),
+        JobStatus::Finished(message) => message,
+        JobStatus::Error(error) => format!("The filament job failed: {}", error),
+    })
+}
+
+#[get("/capabilities")]
+async fn capabilities(
+    printer: SelectedPrinter,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    Ok(web::Json(printer.printer.capabilities()))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct PrinterListing {
+    id: String,
+    default: bool,
+    capabilities: Capabilities,
+}
+
+#[get("/printers")]
+async fn list_printers(
+    registry: web::Data<PrinterRegistry>,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let printers: Vec<_> = registry
+        .iter()
+        .map(|entry| PrinterListing {
+            id: entry.id.clone(),
+            default: entry.id == registry.default_id(),
+            capabilities: entry.printer.capabilities(),
+        })
+        .collect();
+
+    Ok(web::Json(printers))
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryOpts {
+    printer: Option<String>,
+    limit: Option<u32>,
+}
+
+/// Most recent prints first, across all printers unless `printer` is given
+#[get("/history")]
+async fn print_history(
+    history: web::Data<PrintHistory>,
+    token: ClientToken,
+    info: web::Query<HistoryOpts>,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let prints = history
+        .prints(info.printer.as_deref(), Some(info.limit.unwrap_or(50)))
+        .log_error()?;
+    Ok(web::Json(prints))
+}
+
+#[derive(Deserialize, Debug)]
+struct StatsOpts {
+    printer: Option<String>,
+}
+
+#[get("/stats")]
+async fn print_stats(
+    history: web::Data<PrintHistory>,
+    token: ClientToken,
+    info: web::Query<StatsOpts>,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let stats = history.stats(info.printer.as_deref()).log_error()?;
+    Ok(web::Json(stats))
+}
+
+/// The stats as a sentence for Siri
+#[get("/stats/summary")]
+async fn print_stats_summary(
+    history: web::Data<PrintHistory>,
+    token: ClientToken,
+    info: web::Query<StatsOpts>,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    let stats = history.stats(info.printer.as_deref()).log_error()?;
+    Ok(stats.summary())
+}
+
+#[get("/spools")]
+async fn list_spools(
+    spools: web::Data<Spools>,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    Ok(web::Json(spools.list().log_error()?))
+}
+
+#[post("/spools")]
+async fn add_spool(
+    spools: web::Data<Spools>,
+    token: ClientToken,
+    info: web::Json<NewSpool>,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    let remaining = info.remaining_grams.unwrap_or(info.initial_grams);
+    if !(info.initial_grams > 0. && (0. ..=info.initial_grams).contains(&remaining)) {
+        return Err(AnyhowHTTPError::AnyHTTPError {
+            code: 400,
+            message: "initialGrams has to be more than 0, and remainingGrams between 0 and that"
+                .to_string(),
+        });
+    }
+    match spools.add(&info).log_error()? {
+        Some(spool) => Ok(web::Json(spool)),
+        None => Err(AnyhowHTTPError::NotFound404(format!(
+            "No filament profile named {}",
+            info.material
+        ))),
+    }
+}
+
+#[delete("/spools/{id}")]
+async fn remove_spool(
+    spools: web::Data<Spools>,
+    token: ClientToken,
+    id: web::Path<i64>,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    if !spools.remove(*id).log_error()? {
+        return Err(AnyhowHTTPError::NotFound404(format!(
+            "No spool with id {}",
+            id
+        )));
+    }
+    Ok(format!("Removed spool {}", id))
+}
+
+/// The spool on the printer, `null` if none is
+#[get("/spool")]
+async fn mounted_spool(
+    printer: SelectedPrinter,
+    spools: web::Data<Spools>,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::ReadStatus)?;
+    Ok(web::Json(spools.mounted(&printer.id).log_error()?))
+}
+
+#[derive(Deserialize, Debug)]
+struct MountOpts {
+    id: i64,
+}
+
+/// Puts a spool from `/spools` on the printer, so prints are taken off it
+#[post("/spool")]
+async fn mount_spool(
+    printer: SelectedPrinter,
+    spools: web::Data<Spools>,
+    token: ClientToken,
+    info: web::Query<MountOpts>,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::Filament)?;
+    let Some(spool) = spools.mount(info.id, &printer.id).log_error()? else {
+        return Err(AnyhowHTTPError::NotFound404(format!(
+            "No spool with id {}",
+            info.id
+        )));
+    };
+    Ok(format!(
+        "The {} spool is on {} now, with {} grams left",
+        spool.describe(),
+        printer.id,
+        spool.remaining_grams.round()
+    ))
+}
+
+#[get("/tokens")]
+async fn list_tokens(
+    tokens: web::Data<TokenStore>,
+    token: ClientToken,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::Admin)?;
+    Ok(web::Json(tokens.list().log_error()?))
+}
+
+#[derive(Deserialize, Debug)]
+struct NewTokenOpts {
+    /// which phone or shortcut it is for
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+/// The token itself is only in this response
+#[post("/tokens")]
+async fn create_token(
+    tokens: web::Data<TokenStore>,
+    token: ClientToken,
+    info: web::Json<NewTokenOpts>,
+) -> Result<impl Responder, AnyhowHTTPError> {
+    token.require(Scope::Admin)?;
+    let new_token = tokens.create(&info.name, &info.scopes).log_error()?;
+    Ok(web::Json(new_token))
+}
+
+#[delete("/tokens/{id}")]
+async fn revoke_token(
+    tokens: web::Data<TokenStore>,
+    token: ClientToken,
+    id: web::Path<i64>,
+) -> Result<String, AnyhowHTTPError> {
+    token.require(Scope::Admin)?;
+    if !tokens.revoke(*id).log_error()? {
+        return Err(AnyhowHTTPError::NotFound404(format!(
+            "No active token with id {}",
+            id
+        )));
+    }
+    Ok(format!("Revoked token {}", id))
+}
+
+/// All routes. Expects a `PrinterRegistry`, a `dyn Notifier`, a `PrintHistory`,
+/// a `TokenStore`, `Confirmations`, an `IdempotencyCache`, `FilamentProfiles`,
+/// `LoadedFilaments` and `Spools` in the app data.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_printers)
+        .service(list_tokens)
+        .service(create_token)
+        .service(revoke_token)
+        .service(print_history)
+        .service(print_stats)
+        .service(print_stats_summary)
+        .service(list_filaments)
+        .service(list_spools)
+        .service(add_spool)
+        .service(remove_spool)
+        .service(
+            web::scope(&format!("/printers/{{{}}}", PRINTER_ID_PARAM)).configure(printer_routes),
+        )
+        .configure(printer_routes);
+}
+
+/// Routes that act on a single printer. These are mounted both at the root,
+/// where they act on the default printer, and under `/printers/{printer_id}`.
+fn printer_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(job_status)
+        .service(cancel_job)
+        .service(pause_job)
+        .service(resume_job)
+        .service(toggle_pause_job)
+        .service(restart_job)
+        .service(start_job)
+        .service(remove_filament)
+        .service(feed_filament)
+        .service(swap_filament)
+        .service(change_filament_mid_print)
+        .service(continue_filament_job)
+        .service(filament_status)
+        .service(loaded_filament)
+        .service(loaded_filament_summary)
+        .service(mounted_spool)
+        .service(mount_spool)
+        .service(abort_filament_job)
+        .service(capabilities)
+        .service(server_info);
+}
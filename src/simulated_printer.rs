@@ -0,0 +1,582 @@
+//! A printer that only exists in memory, for tests and the mock OctoPrint.
+//!
+//! Temperatures approach their target along an exponential curve and the job
+//! progresses according to its estimated print time. Time comes from tokio,
+//! so tests using paused time (`#[tokio::test(start_paused = true)]`) can run
+//! hours of printing in milliseconds.
+
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail};
+use log::warn;
+use tokio::time::Instant;
+
+use crate::data_defs::printer_job_action::{JobAction, PauseAction};
+use crate::data_defs::printer_job_state::{self, JobState};
+use crate::data_defs::printer_move::{HomeAxis, PrinterMove};
+use crate::data_defs::printer_state::{self, PrinterState};
+use crate::data_defs::printer_tool::Tool;
+use crate::traits::printer_trait::{Capabilities, Printer};
+
+const AMBIENT: f64 = 21.;
+/// seconds for the hot end to get ~63% of the way to its target
+const TOOL_TIME_CONSTANT: f64 = 30.;
+const BED_TIME_CONSTANT: f64 = 90.;
+/// Marlin refuses to extrude below this
+const MIN_EXTRUDE_TEMP: f64 = 170.;
+/// how long cancelling takes before the printer is operational again
+const CANCELLING_TIME: f64 = 5.;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockFile {
+    pub name: String,
+    /// in seconds
+    pub estimated_print_time: f64,
+    /// in mm
+    pub filament_length: f64,
+    /// in cm³
+    pub filament_volume: f64,
+}
+
+impl Default for MockFile {
+    fn default() -> Self {
+        Self {
+            name: "benchy.gcode".to_string(),
+            estimated_print_time: 3600.,
+            filament_length: 2000.,
+            filament_volume: 4.8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimStatus {
+    Operational,
+    Printing,
+    Paused,
+    /// with the simulated seconds left until it's done
+    Cancelling(f64),
+}
+
+/// Everything the mock printer knows. Times are in simulated seconds.
+#[derive(Debug, Clone)]
+pub struct SimState {
+    pub status: SimStatus,
+    /// set by `MockOctoPrint::fail`, the printer stays offline afterwards
+    pub error: Option<String>,
+    pub tool_actual: f64,
+    pub tool_target: f64,
+    pub bed_actual: f64,
+    pub bed_target: f64,
+    /// the selected file
+    pub file: Option<MockFile>,
+    /// how far into the selected file the print is, `None` if it never started
+    pub print_time: Option<f64>,
+    /// last completion, kept after the print is over like OctoPrint does
+    pub completion: Option<f64>,
+    /// total mm of filament extruded through `/api/printer/tool`, retracts are negative
+    pub extruded: f64,
+    pub homed: bool,
+    /// where the print head is, x, y and z in mm
+    pub head: [f64; 3],
+    /// where `save_position` found the print head, like Marlin's `G60`
+    pub saved_head: Option<[f64; 3]>,
+    /// the hot end stays at room temperature whatever its target, like with a broken heater cartridge
+    pub heater_broken: bool,
+    /// this many of the next `job_state` calls fail, like over a flaky connection
+    pub failing_job_polls: u32,
+    /// the print head won't move, like with a backend that refuses to while paused
+    pub moves_refused: bool,
+}
+
+impl Default for SimState {
+    fn default() -> Self {
+        Self {
+            status: SimStatus::Operational,
+            error: None,
+            tool_actual: AMBIENT,
+            tool_target: 0.,
+            bed_actual: AMBIENT,
+            bed_target: 0.,
+            file: Some(MockFile::default()),
+            print_time: None,
+            completion: None,
+            extruded: 0.,
+            homed: false,
+            head: [0.; 3],
+            saved_head: None,
+            heater_broken: false,
+            failing_job_polls: 0,
+            moves_refused: false,
+        }
+    }
+}
+
+impl SimState {
+    /// Moves the simulation `dt` seconds forward
+    pub fn advance(&mut self, dt: f64) {
+        let tool_target = if self.heater_broken {
+            0.
+        } else {
+            self.tool_target
+        };
+        self.tool_actual = approach(self.tool_actual, tool_target, TOOL_TIME_CONSTANT, dt);
+        self.bed_actual = approach(self.bed_actual, self.bed_target, BED_TIME_CONSTANT, dt);
+
+        match self.status {
+            SimStatus::Printing => {
+                let estimated = self.file.as_ref().map_or(0., |f| f.estimated_print_time);
+                let print_time = self.print_time.unwrap_or(0.) + dt;
+                self.print_time = Some(print_time.min(estimated));
+                self.completion = Some((print_time / estimated * 100.).min(100.));
+                if print_time >= estimated {
+                    self.end_print();
+                }
+            }
+            SimStatus::Cancelling(left) if left <= dt => {
+                self.end_print();
+                self.completion = None;
+                self.print_time = None;
+            }
+            SimStatus::Cancelling(left) => self.status = SimStatus::Cancelling(left - dt),
+            SimStatus::Operational | SimStatus::Paused => {}
+        }
+    }
+
+    fn end_print(&mut self) {
+        self.status = SimStatus::Operational;
+        self.tool_target = 0.;
+        self.bed_target = 0.;
+    }
+
+    fn start_print(&mut self) {
+        self.status = SimStatus::Printing;
+        self.print_time = Some(0.);
+        self.completion = Some(0.);
+        // what the start gcode of a PLA print would do
+        self.tool_target = 200.;
+        self.bed_target = 60.;
+    }
+
+    /// Moving and extruding by hand only works when idle or paused
+    fn busy(&self) -> bool {
+        matches!(self.status, SimStatus::Printing | SimStatus::Cancelling(_))
+    }
+
+    fn operational(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub(crate) fn printer_state(&self) -> PrinterState {
+        let status = if self.operational() {
+            self.status
+        } else {
+            SimStatus::Operational
+        };
+
+        let text = match (&self.error, status) {
+            (Some(_), _) => "Offline after error",
+            (None, SimStatus::Operational) => "Operational",
+            (None, SimStatus::Printing) => "Printing",
+            (None, SimStatus::Paused) => "Paused",
+            (None, SimStatus::Cancelling(_)) => "Cancelling",
+        };
+
+        let operational = self.operational();
+        PrinterState {
+            sd: printer_state::Sd { ready: false },
+            state: printer_state::State {
+                error: self.error.clone().unwrap_or_default(),
+                flags: printer_state::Flags {
+                    cancelling: operational && matches!(status, SimStatus::Cancelling(_)),
+                    closed_or_error: !operational,
+                    error: !operational,
+                    finishing: false,
+                    operational,
+                    paused: operational && status == SimStatus::Paused,
+                    pausing: false,
+                    printing: operational && status == SimStatus::Printing,
+                    ready: operational && status == SimStatus::Operational,
+                    resuming: false,
+                    sd_ready: false,
+                },
+                text: text.to_string(),
+            },
+            temperature: printer_state::Temperature {
+                bed: printer_state::Bed {
+                    actual: round_temperature(self.bed_actual),
+                    offset: 0,
+                    target: self.bed_target,
+                },
+                tool0: printer_state::Tool0 {
+                    actual: round_temperature(self.tool_actual),
+                    offset: 0,
+                    target: self.tool_target,
+                },
+            },
+        }
+    }
+
+    pub(crate) fn job_state(&self) -> JobState {
+        let file = self.file.as_ref();
+        JobState {
+            job: printer_job_state::Job {
+                file: printer_job_state::File {
+                    name: file.map(|f| f.name.clone()),
+                    origin: file.map(|_| "local".to_string()),
+                    size: None,
+                    date: None,
+                },
+                estimated_print_time: file.map(|f| f.estimated_print_time),
+                average_print_time: None,
+                filament: file.map(|f| printer_job_state::Filament {
+                    tool0: Some(printer_job_state::Tool0 {
+                        length: f.filament_length,
+                        volume: f.filament_volume,
+                    }),
+                }),
+                last_print_time: None,
+                user: file.map(|_| "mock".to_string()),
+            },
+            progress: printer_job_state::Progress {
+                completion: self.completion,
+                filepos: None,
+                print_time: self.print_time.map(|t| t.round() as i64),
+                print_time_left: match (self.status, file, self.print_time) {
+                    (SimStatus::Printing | SimStatus::Paused, Some(file), Some(print_time)) => {
+                        Some((file.estimated_print_time - print_time).round() as i64)
+                    }
+                    _ => None,
+                },
+            },
+            state: self.printer_state().state.text,
+            error: self.error.clone(),
+        }
+    }
+
+    /// Applies a job command, or returns why OctoPrint would answer 409
+    pub(crate) fn job_command(&mut self, action: JobAction) -> Result<(), &'static str> {
+        self.ensure_operational()?;
+
+        match (action, self.status) {
+            (JobAction::Start, SimStatus::Operational) if self.file.is_some() => self.start_print(),
+            (JobAction::Start, SimStatus::Operational) => return Err("No file selected"),
+            (JobAction::Start, _) => return Err("Printer is busy"),
+            (JobAction::Cancel, SimStatus::Printing | SimStatus::Paused) => {
+                self.status = SimStatus::Cancelling(CANCELLING_TIME)
+            }
+            (JobAction::Cancel, _) => return Err("Printer is neither printing nor paused"),
+            (JobAction::Restart, SimStatus::Paused) => self.start_print(),
+            (JobAction::Restart, _) => return Err("Printer is not paused"),
+            (
+                JobAction::Pause {
+                    action: PauseAction::Pause | PauseAction::Toggle,
+                },
+                SimStatus::Printing,
+            ) => self.status = SimStatus::Paused,
+            (
+                JobAction::Pause {
+                    action: PauseAction::Resume | PauseAction::Toggle,
+                },
+                SimStatus::Paused,
+            ) => self.status = SimStatus::Printing,
+            (JobAction::Pause { .. }, _) => return Err("Printer is neither printing nor paused"),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn tool_command(&mut self, command: Tool) -> Result<(), &'static str> {
+        self.ensure_operational()?;
+
+        match command {
+            Tool::Target { targets } => self.tool_target = targets.tool0 as f64,
+            Tool::Extrude { .. } if self.busy() => return Err("Printer is currently printing"),
+            Tool::Extrude { amount, .. } => {
+                if self.tool_actual < MIN_EXTRUDE_TEMP {
+                    warn!("Simulated printer: cold extrusion prevented");
+                } else {
+                    self.extruded += amount;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn printhead_command(&mut self, command: PrinterMove) -> Result<(), &'static str> {
+        self.ensure_can_move()?;
+        match command {
+            PrinterMove::Home { .. } => {
+                self.homed = true;
+                self.head = [0.; 3];
+            }
+            PrinterMove::Move {
+                x, y, z, absolute, ..
+            } => {
+                for (position, value) in self.head.iter_mut().zip([x, y, z]) {
+                    match (value, absolute) {
+                        (Some(value), Some(true)) => *position = value,
+                        (Some(value), _) => *position += value,
+                        (None, _) => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_position(&mut self) -> Result<(), &'static str> {
+        self.ensure_can_move()?;
+        self.saved_head = Some(self.head);
+        Ok(())
+    }
+
+    /// Only `axes` go back, like `G61 X Y`
+    pub(crate) fn restore_position(&mut self, axes: &[HomeAxis]) -> Result<(), &'static str> {
+        self.ensure_can_move()?;
+        let saved = self.saved_head.ok_or("No saved position to go back to")?;
+        for axis in axes {
+            let i = match axis {
+                HomeAxis::X => 0,
+                HomeAxis::Y => 1,
+                HomeAxis::Z => 2,
+            };
+            self.head[i] = saved[i];
+        }
+        Ok(())
+    }
+
+    fn ensure_can_move(&self) -> Result<(), &'static str> {
+        self.ensure_operational()?;
+        if self.busy() {
+            return Err("Printer is currently printing");
+        }
+        if self.moves_refused {
+            return Err("Printer refused to move");
+        }
+        Ok(())
+    }
+
+    /// Moves and extrusions happen at once here, so there is nothing to stop
+    pub(crate) fn quick_stop(&mut self) -> Result<(), &'static str> {
+        self.ensure_operational()
+    }
+
+    fn ensure_operational(&self) -> Result<(), &'static str> {
+        match self.operational() {
+            true => Ok(()),
+            false => Err("Printer is not operational"),
+        }
+    }
+}
+
+/// `actual` moves towards `target` like a first order system
+fn approach(actual: f64, target: f64, time_constant: f64, dt: f64) -> f64 {
+    // a target of 0 means off, so it cools down to room temperature
+    let target = target.max(AMBIENT);
+    target + (actual - target) * (-dt / time_constant).exp()
+}
+
+fn round_temperature(t: f64) -> f64 {
+    (t * 100.).round() / 100.
+}
+
+pub struct SimulatedPrinter {
+    /// how many simulated seconds pass per second on the clock
+    speed: f64,
+    state: Mutex<(SimState, Instant)>,
+}
+
+impl SimulatedPrinter {
+    pub fn new(speed: f64) -> Self {
+        Self {
+            speed,
+            state: Mutex::new((SimState::default(), Instant::now())),
+        }
+    }
+
+    /// Runs `f` on the simulation after bringing it up to date
+    pub fn update<T>(&self, f: impl FnOnce(&mut SimState) -> T) -> T {
+        let mut guard = self.state.lock().unwrap();
+        let (state, last_update) = &mut *guard;
+        let now = Instant::now();
+        state.advance((now - *last_update).as_secs_f64() * self.speed);
+        *last_update = now;
+        f(state)
+    }
+
+    pub fn snapshot(&self) -> SimState {
+        self.update(|state| state.clone())
+    }
+
+    /// Simulates e.g. a thermal runaway. The printer stays offline afterwards.
+    pub fn fail(&self, error: impl Into<String>) {
+        self.update(|state| {
+            state.error = Some(error.into());
+            state.tool_target = 0.;
+            state.bed_target = 0.;
+        })
+    }
+
+    fn command(
+        &self,
+        f: impl FnOnce(&mut SimState) -> Result<(), &'static str>,
+    ) -> anyhow::Result<()> {
+        self.update(f).map_err(|reason| anyhow!(reason))
+    }
+}
+
+/// The api key is ignored, there is nobody to check it
+#[async_trait::async_trait]
+impl Printer for SimulatedPrinter {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            pause: true,
+            restart: true,
+            raw_extrusion: true,
+            bed_heater: true,
+            multiple_tools: false,
+            webcam: false,
+            file_upload: false,
+        }
+    }
+
+    async fn printer_state(&self, _api_key: &str) -> anyhow::Result<PrinterState> {
+        Ok(self.update(|state| state.printer_state()))
+    }
+
+    async fn job_state(&self, _api_key: &str) -> anyhow::Result<JobState> {
+        self.update(|state| {
+            if state.failing_job_polls > 0 {
+                state.failing_job_polls -= 1;
+                bail!("Timed out asking for the job");
+            }
+            Ok(state.job_state())
+        })
+    }
+
+    async fn tool_command(&self, _api_key: &str, command: Tool) -> anyhow::Result<()> {
+        self.command(|state| state.tool_command(command))
+    }
+
+    async fn printhead_command(&self, _api_key: &str, command: PrinterMove) -> anyhow::Result<()> {
+        self.command(|state| state.printhead_command(command))
+    }
+
+    async fn save_position(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.command(|state| state.save_position())
+    }
+
+    async fn restore_position(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.command(|state| state.restore_position(&[HomeAxis::X, HomeAxis::Y, HomeAxis::Z]))
+    }
+
+    async fn quick_stop(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.command(|state| state.quick_stop())
+    }
+
+    async fn cancel_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.command(|state| state.job_command(JobAction::Cancel))
+    }
+
+    async fn start_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.command(|state| state.job_command(JobAction::Start))
+    }
+
+    async fn restart_job(&self, _api_key: &str) -> anyhow::Result<()> {
+        self.command(|state| state.job_command(JobAction::Restart))
+    }
+
+    async fn pause_job(&self, _api_key: &str, action: PauseAction) -> anyhow::Result<()> {
+        self.command(|state| state.job_command(JobAction::Pause { action }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heating() {
+        let mut state = SimState::default();
+        state
+            .tool_command(Tool::Extrude {
+                amount: 10.,
+                speed: None,
+            })
+            .unwrap();
+        assert_eq!(state.extruded, 0., "cold extrusion should be prevented");
+
+        state.tool_target = 200.;
+        state.advance(TOOL_TIME_CONSTANT);
+        assert!(state.tool_actual > 100. && state.tool_actual < 200.);
+        state.advance(10. * TOOL_TIME_CONSTANT);
+        assert!((state.tool_actual - 200.).abs() < 0.1);
+
+        state
+            .tool_command(Tool::Extrude {
+                amount: 10.,
+                speed: None,
+            })
+            .unwrap();
+        assert_eq!(state.extruded, 10.);
+
+        state.tool_target = 0.;
+        state.advance(20. * TOOL_TIME_CONSTANT);
+        assert!((state.tool_actual - AMBIENT).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_job_progress() {
+        let mut state = SimState::default();
+        state.job_command(JobAction::Start).unwrap();
+        assert_eq!(state.job_command(JobAction::Start), Err("Printer is busy"));
+
+        state.advance(900.);
+        assert_eq!(state.completion, Some(25.));
+        assert_eq!(state.job_state().progress.print_time_left, Some(2700));
+
+        let pause = JobAction::Pause {
+            action: PauseAction::Toggle,
+        };
+        state.job_command(pause.clone()).unwrap();
+        state.advance(900.);
+        assert_eq!(state.completion, Some(25.));
+        state.job_command(pause).unwrap();
+
+        state.advance(3600.);
+        assert_eq!(state.status, SimStatus::Operational);
+        assert_eq!(state.completion, Some(100.));
+        assert_eq!(state.tool_target, 0.);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut state = SimState::default();
+        assert!(state.job_command(JobAction::Cancel).is_err());
+
+        state.job_command(JobAction::Start).unwrap();
+        state.advance(60.);
+        state.job_command(JobAction::Cancel).unwrap();
+        assert!(state.printer_state().state.flags.cancelling);
+
+        state.advance(CANCELLING_TIME);
+        assert_eq!(state.status, SimStatus::Operational);
+        assert_eq!(state.completion, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock() {
+        let printer = SimulatedPrinter::new(1.);
+        printer.start_job("").await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_secs(1800)).await;
+        let job = printer.job_state("").await.unwrap();
+        assert_eq!(job.progress.completion, Some(50.));
+        assert_eq!(job.progress.print_time, Some(1800));
+
+        printer.fail("Thermal Runaway");
+        assert!(printer.pause_job("", PauseAction::Pause).await.is_err());
+        let state = printer.printer_state("").await.unwrap();
+        assert!(state.state.flags.error && !state.state.flags.printing);
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SpoolsConfig;
+use crate::events::PrinterEvent;
+use crate::filaments::{FilamentProfile, FilamentProfiles, HotEndTemperature};
+use crate::storage::Database;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Spool {
+    pub id: i64,
+    /// name of the filament profile
+    pub material: String,
+    pub colour: String,
+    /// of the filament alone, without the spool
+    pub initial_grams: f64,
+    pub remaining_grams: f64,
+    /// the printer it's on, if any
+    pub printer: Option<String>,
+    pub added_at: DateTime<Utc>,
+}
+
+impl Spool {
+    /// e.g. "red PLA"
+    pub fn describe(&self) -> String {
+        format!("{} {}", self.colour, self.material)
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            material: row.get(1)?,
+            colour: row.get(2)?,
+            initial_grams: row.get(3)?,
+            remaining_grams: row.get(4)?,
+            printer: row.get(5)?,
+            added_at: row.get(6)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSpool {
+    /// name or alias of a filament profile
+    pub material: String,
+    pub colour: String,
+    pub initial_grams: f64,
+    /// for a spool that has been used already, `initial_grams` if not given
+    pub remaining_grams: Option<f64>,
+}
+
+const SPOOL_COLUMNS: &str =
+    "id, material, colour, initial_grams, remaining_grams, printer, added_at";
+
+/// The spools on hand and how much is left on each. Only prints this server
+/// has seen are deducted, so filament loaded and purged by hand isn't counted.
+pub struct Spools {
+    db: Arc<Database>,
+    profiles: FilamentProfiles,
+    config: SpoolsConfig,
+}
+
+impl Spools {
+    pub fn new(db: Arc<Database>, profiles: FilamentProfiles, config: SpoolsConfig) -> Self {
+        Self {
+            db,
+            profiles,
+            config,
+        }
+    }
+
+    /// `None` if the material isn't a known filament profile
+    pub fn add(&self, spool: &NewSpool) -> anyhow::Result<Option<Spool>> {
+        let Some(profile) = self.profiles.find(&spool.material) else {
+            return Ok(None);
+        };
+        let remaining = spool.remaining_grams.unwrap_or(spool.initial_grams);
+        let id = self.db.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO spools (material, colour, initial_grams, remaining_grams, added_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    profile.name,
+                    spool.colour,
+                    spool.initial_grams,
+                    remaining,
+                    Utc::now()
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })?;
+        self.get(id)
+    }
+
+    pub fn get(&self, id: i64) -> anyhow::Result<Option<Spool>> {
+        self.db.with_conn(|conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM spools WHERE id = ?1", SPOOL_COLUMNS),
+                params![id],
+                Spool::from_row,
+            )
+            .optional()
+        })
+    }
+
+    /// Oldest first
+    pub fn list(&self) -> anyhow::Result<Vec<Spool>> {
+        self.db.with_conn(|conn| {
+            let mut statement =
+                conn.prepare(&format!("SELECT {} FROM spools ORDER BY id", SPOOL_COLUMNS))?;
+            let rows = statement.query_map([], Spool::from_row)?;
+            rows.collect()
+        })
+    }
+
+    /// `false` if there is no such spool
+    pub fn remove(&self, id: i64) -> anyhow::Result<bool> {
+        let changed = self
+            .db
+            .with_conn(|conn| conn.execute("DELETE FROM spools WHERE id = ?1", params![id]))?;
+        Ok(changed > 0)
+    }
+
+    /// Puts the spool on `printer`, taking off whichever was on it before.
+    /// `None` if there is no such spool.
+    pub fn mount(&self, id: i64, printer: &str) -> anyhow::Result<Option<Spool>> {
+        if self.get(id)?.is_none() {
+            return Ok(None);
+        }
+        self.db.with_conn(|conn| {
+            conn.execute(
+                "UPDATE spools SET printer = NULL WHERE printer = ?1 AND id != ?2",
+                params![printer, id],
+            )?;
+            conn.execute(
+                "UPDATE spools SET printer = ?1 WHERE id = ?2",
+                params![printer, id],
+            )
+        })?;
+        self.get(id)
+    }
+
+    pub fn mounted(&self, printer: &str) -> anyhow::Result<Option<Spool>> {
+        self.db.with_conn(|conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM spools WHERE printer = ?1", SPOOL_COLUMNS),
+                params![printer],
+                Spool::from_row,
+            )
+            .optional()
+        })
+    }
+
+    /// How much `length` mm of the spool's filament weighs
+    pub fn grams(&self, spool: &Spool, length: f64) -> f64 {
+        self.profile(spool).grams(length)
+    }
+
+    /// Takes what a print used off the spool on `printer`. Returns the warning to send
+    /// if that leaves less than `low_grams`, which only happens once per spool.
+    pub fn use_filament(&self, printer: &str, length: f64) -> anyhow::Result<Option<PrinterEvent>> {
+        let Some(spool) = self.mounted(printer)? else {
+            return Ok(None);
+        };
+        let remaining = (spool.remaining_grams - self.grams(&spool, length)).max(0.);
+        self.db.with_conn(|conn| {
+            conn.execute(
+                "UPDATE spools SET remaining_grams = ?1 WHERE id = ?2",
+                params![remaining, spool.id],
+            )
+        })?;
+
+        let low = self.config.low_grams;
+        Ok(
+            (spool.remaining_grams >= low && remaining < low).then(|| PrinterEvent::LowFilament {
+                spool: spool.describe(),
+                remaining_grams: remaining,
+            }),
+        )
+    }
+
+    /// The spool's profile, or PLA's numbers if the profile has been taken out of the config since
+    fn profile(&self, spool: &Spool) -> FilamentProfile {
+        self.profiles
+            .find(&spool.material)
+            .cloned()
+            .unwrap_or_else(|| {
+                FilamentProfile::new(&spool.material, HotEndTemperature::new(200).unwrap())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spools() {
+        let spools = Spools::new(
+            Arc::new(Database::open_in_memory().unwrap()),
+            FilamentProfiles::default(),
+            SpoolsConfig { low_grams: 100. },
+        );
+        let new_spool = |material: &str, colour: &str, remaining| NewSpool {
+            material: material.to_string(),
+            colour: colour.to_string(),
+            initial_grams: 1000.,
+            remaining_grams: remaining,
+        };
+
+        assert!(spools
+            .add(&new_spool("Nylon", "black", None))
+            .unwrap()
+            .is_none());
+        let red = spools.add(&new_spool("pla", "red", None)).unwrap().unwrap();
+        assert_eq!(red.material, "PLA");
+        assert_eq!(red.remaining_grams, 1000.);
+        let blue = spools
+            .add(&new_spool("PETG", "blue", Some(150.)))
+            .unwrap()
+            .unwrap();
+
+        // nothing mounted, nothing to deduct from
+        assert_eq!(spools.use_filament("ender", 1000.).unwrap(), None);
+
+        spools.mount(red.id, "ender").unwrap().unwrap();
+        spools.mount(blue.id, "ender").unwrap().unwrap();
+        assert_eq!(spools.mounted("ender").unwrap().unwrap().id, blue.id);
+        assert_eq!(spools.get(red.id).unwrap().unwrap().printer, None);
+        assert!(spools.mount(99, "ender").unwrap().is_none());
+
+        // about 30 g of PETG
+        assert_eq!(spools.use_filament("ender", 10_000.).unwrap(), None);
+        let event = spools.use_filament("ender", 10_000.).unwrap().unwrap();
+        assert!(
+            matches!(&event, PrinterEvent::LowFilament { spool, remaining_grams }
+                if spool == "blue PETG" && remaining_grams.round() == 89.),
+            "{:?}",
+            event
+        );
+        assert_eq!(
+            spools.use_filament("ender", 10_000.).unwrap(),
+            None,
+            "only warned once"
+        );
+        spools.use_filament("ender", 100_000.).unwrap();
+        assert_eq!(spools.get(blue.id).unwrap().unwrap().remaining_grams, 0.);
+
+        assert_eq!(spools.list().unwrap().len(), 2);
+        assert!(spools.remove(red.id).unwrap());
+        assert!(!spools.remove(red.id).unwrap());
+        assert_eq!(
+            spools.list().unwrap(),
+            vec![spools.get(blue.id).unwrap().unwrap()]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use rusqlite::Connection;
+
+/// Tables are created on open if they don't exist yet
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS prints (
+    id INTEGER PRIMARY KEY,
+    printer TEXT NOT NULL,
+    file_name TEXT,
+    origin TEXT,
+    user TEXT,
+    started_at TEXT NOT NULL,
+    ended_at TEXT NOT NULL,
+    duration INTEGER,
+    outcome TEXT NOT NULL,
+    error TEXT,
+    filament_length REAL,
+    filament_volume REAL
+);
+
+CREATE TABLE IF NOT EXISTS tokens (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    last_used_at TEXT,
+    revoked_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS spools (
+    id INTEGER PRIMARY KEY,
+    material TEXT NOT NULL,
+    colour TEXT NOT NULL,
+    initial_grams REAL NOT NULL,
+    remaining_grams REAL NOT NULL,
+    printer TEXT UNIQUE,
+    added_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS loaded_filament (
+    printer TEXT PRIMARY KEY,
+    filament TEXT NOT NULL,
+    loaded_at TEXT NOT NULL
+);
+";
+
+/// Local SQLite database for everything that should survive a restart.
+/// Queries are small and quick, so they run directly on the calling thread.
+pub struct Database {
+    conn: Mutex<Connection>,
+}
+
+impl Database {
+    /// Creates the file (and its directory) if needed
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> anyhow::Result<T> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("Database lock poisoned"))?;
+        Ok(f(&conn)?)
+    }
+}
@@ -1,4 +1,9 @@
+use crate::events::PrinterEvent;
+
 #[async_trait::async_trait]
 pub trait Notifier: Send + Sync {
-    async fn notify(&self) -> anyhow::Result<()>;
+    /// `printer_id` is the id of the printer the event happened on.
+    /// Implementations decide themselves which events they care about,
+    /// and should just return `Ok(())` for the rest.
+    async fn notify(&self, printer_id: &str, event: &PrinterEvent) -> anyhow::Result<()>;
 }
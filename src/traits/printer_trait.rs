@@ -1,14 +1,141 @@
+use serde::Serialize;
+
 use crate::{
-    data_defs::{printer_job_state::JobState, printer_state::PrinterState},
-    filaments::Filament,
+    config::{HeatingConfig, ParkPosition},
+    data_defs::{
+        printer_job_action::PauseAction, printer_job_state::JobState, printer_move::PrinterMove,
+        printer_state::PrinterState, printer_tool::Tool,
+    },
+    filament_change,
+    filaments::FilamentProfile,
+    utils::job_running::Progress,
 };
 
+/// What a backend's api lets this server do.
+/// Whether the printer itself has a webcam or a second extruder isn't checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub pause: bool,
+    /// starting a paused print over from the beginning
+    pub restart: bool,
+    /// extruding a given length, which loading and unloading filament needs
+    pub raw_extrusion: bool,
+    pub bed_heater: bool,
+    /// heating and extruding with tools other than `tool0`. No backend drives those yet.
+    pub multiple_tools: bool,
+    pub webcam: bool,
+    pub file_upload: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Pause,
+    Restart,
+    RawExtrusion,
+    BedHeater,
+    MultipleTools,
+    Webcam,
+    FileUpload,
+}
+
+impl Capabilities {
+    pub fn has(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Pause => self.pause,
+            Capability::Restart => self.restart,
+            Capability::RawExtrusion => self.raw_extrusion,
+            Capability::BedHeater => self.bed_heater,
+            Capability::MultipleTools => self.multiple_tools,
+            Capability::Webcam => self.webcam,
+            Capability::FileUpload => self.file_upload,
+        }
+    }
+}
+
+impl Capability {
+    /// For Siri, as in "Printer ender can't ..."
+    pub fn spoken(self) -> &'static str {
+        match self {
+            Self::Pause => "pause prints",
+            Self::Restart => "restart prints",
+            Self::RawExtrusion => "load or unload filament",
+            Self::BedHeater => "heat the bed",
+            Self::MultipleTools => "switch between tools",
+            Self::Webcam => "show a webcam",
+            Self::FileUpload => "receive files",
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Printer: Send + Sync {
+    fn capabilities(&self) -> Capabilities;
     async fn printer_state(&self, api_key: &str) -> anyhow::Result<PrinterState>;
-    async fn retract_filament(&self, api_key: &str, filament: Filament) -> anyhow::Result<()>;
-    async fn feed_filament(&self, api_key: &str, filament: Filament) -> anyhow::Result<()>;
-    async fn cool_down(&self, api_key: &str) -> anyhow::Result<()>;
     async fn job_state(&self, api_key: &str) -> anyhow::Result<JobState>;
+    /// set the hot end temperature or extrude
+    async fn tool_command(&self, api_key: &str, command: Tool) -> anyhow::Result<()>;
+    /// home or move the print head
+    async fn printhead_command(&self, api_key: &str, command: PrinterMove) -> anyhow::Result<()>;
+    /// remembers where the print head is, for `restore_position`
+    async fn save_position(&self, api_key: &str) -> anyhow::Result<()>;
+    /// moves the print head back to where `save_position` found it,
+    /// X and Y before Z so that it doesn't come down on the print
+    async fn restore_position(&self, api_key: &str) -> anyhow::Result<()>;
+    /// stops moves that are in progress, like a long extrusion, without stopping the printer
+    async fn quick_stop(&self, api_key: &str) -> anyhow::Result<()>;
     async fn cancel_job(&self, api_key: &str) -> anyhow::Result<()>;
+    /// starts the currently selected file
+    async fn start_job(&self, api_key: &str) -> anyhow::Result<()>;
+    /// restarts a paused job from the beginning
+    async fn restart_job(&self, api_key: &str) -> anyhow::Result<()>;
+    async fn pause_job(&self, api_key: &str, action: PauseAction) -> anyhow::Result<()>;
+
+    async fn retract_filament(
+        &self,
+        api_key: &str,
+        filament: &FilamentProfile,
+        heating: &HeatingConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<()> {
+        filament_change::retract_filament(self, api_key, filament, heating, progress).await
+    }
+
+    async fn feed_filament(
+        &self,
+        api_key: &str,
+        filament: &FilamentProfile,
+        heating: &HeatingConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<()> {
+        filament_change::feed_filament(self, api_key, filament, heating, progress).await
+    }
+
+    async fn swap_filament(
+        &self,
+        api_key: &str,
+        from: &FilamentProfile,
+        to: &FilamentProfile,
+        heating: &HeatingConfig,
+        progress: &Progress,
+    ) -> anyhow::Result<()> {
+        filament_change::swap_filament(self, api_key, from, to, heating, progress).await
+    }
+
+    async fn change_filament_mid_print(
+        &self,
+        api_key: &str,
+        from: &FilamentProfile,
+        to: &FilamentProfile,
+        heating: &HeatingConfig,
+        park: &ParkPosition,
+        progress: &Progress,
+    ) -> anyhow::Result<()> {
+        filament_change::change_filament_mid_print(self, api_key, from, to, heating, park, progress)
+            .await
+    }
+
+    async fn cool_down(&self, api_key: &str) -> anyhow::Result<()> {
+        filament_change::cool_down(self, api_key).await
+    }
 }
@@ -8,8 +8,16 @@ pub enum AnyhowHTTPError {
     InternalServerError500(String),
     #[error("Unauthorized 401: {0}")]
     Unauthorized401(String),
+    /// a valid token without the scope the route needs
+    #[error("Forbidden 403: {0}")]
+    Forbidden403(String),
+    #[error("Not Found 404: {0}")]
+    NotFound404(String),
     #[error("Conflict 409: {0}")]
     Conflict409(String),
+    /// the printer (or its backend) can't do this at all
+    #[error("Not Implemented 501: {0}")]
+    NotImplemented501(String),
     #[error("HTTPError: {code} {message}")]
     AnyHTTPError { code: u16, message: String },
 }
@@ -22,6 +30,9 @@ impl actix_web::error::ResponseError for AnyhowHTTPError {
             }
             Self::Conflict409(e) => actix_web::HttpResponse::Conflict().body(e.clone()),
             Self::Unauthorized401(e) => actix_web::HttpResponse::Unauthorized().body(e.clone()),
+            Self::Forbidden403(e) => actix_web::HttpResponse::Forbidden().body(e.clone()),
+            Self::NotFound404(e) => actix_web::HttpResponse::NotFound().body(e.clone()),
+            Self::NotImplemented501(e) => actix_web::HttpResponse::NotImplemented().body(e.clone()),
             Self::AnyHTTPError { code, message } => actix_web::HttpResponse::build(
                 actix_web::http::StatusCode::from_u16(*code).unwrap(),
             )
@@ -34,8 +45,28 @@ impl actix_web::error::ResponseError for AnyhowHTTPError {
     }
 }
 
+impl AnyhowHTTPError {
+    /// OctoPrint answers 409 when the printer is in the wrong state for a command
+    /// (e.g. pausing when nothing is printing). This replaces the raw upstream
+    /// error with a message that makes sense when read out by Siri.
+    pub fn with_conflict_message(self, message: &str) -> Self {
+        match self {
+            Self::AnyHTTPError { code: 409, .. } | Self::Conflict409(_) => {
+                Self::Conflict409(message.to_string())
+            }
+            e => e,
+        }
+    }
+}
+
 impl From<anyhow::Error> for AnyhowHTTPError {
     fn from(e: anyhow::Error) -> Self {
+        // backends that don't speak http to the printer report conflicts this way
+        let e = match e.downcast::<AnyhowHTTPError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+
         match e.downcast::<reqwest::Error>() {
             Ok(e) => {
                 if let Some(status) = e.status() {
@@ -51,3 +82,27 @@ impl From<anyhow::Error> for AnyhowHTTPError {
         // Self::InternalServerError500(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_conflict_message() {
+        let e = AnyhowHTTPError::AnyHTTPError {
+            code: 409,
+            message: "HTTP status client error (409 CONFLICT)".to_string(),
+        }
+        .with_conflict_message("Nothing is currently printing");
+        assert!(
+            matches!(e, AnyhowHTTPError::Conflict409(m) if m == "Nothing is currently printing")
+        );
+
+        let e = AnyhowHTTPError::AnyHTTPError {
+            code: 403,
+            message: "Forbidden".to_string(),
+        }
+        .with_conflict_message("Nothing is currently printing");
+        assert!(matches!(e, AnyhowHTTPError::AnyHTTPError { code: 403, .. }));
+    }
+}
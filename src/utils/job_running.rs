@@ -1,6 +1,12 @@
 use std::future::Future;
+use std::mem;
+use std::sync::Arc;
 
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify};
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 use super::http_errors::AnyhowHTTPError;
 
@@ -21,12 +27,198 @@ impl<E: ToString> From<Result<String, E>> for JobStatus {
     }
 }
 
+/// What a filament job is doing right now
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    #[default]
+    Starting,
+    Pausing,
+    Homing,
+    /// moving the print head out of the way
+    Parking,
+    Heating,
+    Loading,
+    Unloading,
+    /// until `LongRunningJob::proceed` is called
+    WaitingForFilament,
+    Resuming,
+    Finished,
+}
+
+impl Phase {
+    fn spoken(self) -> &'static str {
+        match self {
+            Self::Starting => "Getting started",
+            Self::Pausing => "Pausing the print",
+            Self::Homing => "Homing the print head",
+            Self::Parking => "Moving the print head out of the way",
+            Self::Heating => "Heating the hot end",
+            Self::Loading => "Loading filament",
+            Self::Unloading => "Unloading filament",
+            Self::WaitingForFilament => {
+                "Waiting for the new filament, say continue once it's inserted"
+            }
+            Self::Resuming => "Resuming the print",
+            Self::Finished => "Finished",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobProgress {
+    pub phase: Phase,
+    /// of the hot end, once it has been read
+    pub actual_temperature: Option<f64>,
+    pub target_temperature: Option<f64>,
+    /// a rough estimate for the whole job, heating takes up most of it
+    pub percent: u8,
+    /// whether the job has moved the print head off a paused print.
+    /// Whoever cancels the job has to put it back.
+    #[serde(skip)]
+    pub parked: bool,
+}
+
+impl JobProgress {
+    /// For Siri, e.g. "Heating the hot end, 150 of 200 degrees. 60% done"
+    pub fn summary(&self) -> String {
+        let phase = match (self.phase, self.actual_temperature, self.target_temperature) {
+            (Phase::Heating, Some(actual), Some(target)) => format!(
+                "{}, {} of {} degrees",
+                self.phase.spoken(),
+                actual.round(),
+                target.round()
+            ),
+            (phase, _, _) => phase.spoken().to_string(),
+        };
+        format!("{}. {}% done", phase, self.percent)
+    }
+}
+
+/// The sending side of a job's progress. Updates are dropped if nobody is listening.
+#[derive(Debug)]
+pub struct Progress {
+    sender: watch::Sender<JobProgress>,
+    proceed: Arc<Notify>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            sender: watch::channel(JobProgress::default()).0,
+            proceed: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl Progress {
+    pub fn phase(&self, phase: Phase, percent: u8) {
+        self.sender.send_modify(|progress| {
+            progress.phase = phase;
+            progress.percent = percent;
+        });
+    }
+
+    pub fn temperature(&self, actual: f64, target: f64, percent: u8) {
+        self.sender.send_modify(|progress| {
+            progress.actual_temperature = Some(actual);
+            progress.target_temperature = Some(target);
+            progress.percent = percent;
+        });
+    }
+
+    pub fn parked(&self, parked: bool) {
+        self.sender.send_modify(|progress| progress.parked = parked);
+    }
+
+    pub fn current(&self) -> JobProgress {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<JobProgress> {
+        self.sender.subscribe()
+    }
+
+    /// Sets `Phase::WaitingForFilament` and waits until `LongRunningJob::proceed` is called
+    pub async fn wait_for_filament(&self, percent: u8) {
+        self.phase(Phase::WaitingForFilament, percent);
+        self.proceed.notified().await
+    }
+}
+
+#[derive(Default)]
 pub struct LongRunningJob {
     pub job: Option<JoinHandle<anyhow::Result<String>>>,
+    pub progress: Option<watch::Receiver<JobProgress>>,
+    cancel: Option<CancellationToken>,
+    proceed: Option<Arc<Notify>>,
+}
+
+impl LongRunningJob {
+    /// A finished job is only reported once, after that there is no job
+    pub async fn take_status(&mut self) -> anyhow::Result<JobStatus> {
+        Ok(match &self.job {
+            None => JobStatus::NoJob,
+            Some(job) if job.is_finished() => {
+                self.progress = None;
+                // we by now verified that the job exists and is finished
+                mem::take(&mut self.job).unwrap().await?.into()
+            }
+            Some(_) => JobStatus::Running,
+        })
+    }
+
+    /// Stops a running job at the next point where it waits for the printer,
+    /// and waits until it has. Returns how far it got, `None` if no job was running.
+    /// Whatever the job already sent to the printer is up to the caller to undo.
+    pub async fn cancel(&mut self) -> Option<JobProgress> {
+        match (&self.job, &self.cancel) {
+            (Some(job), Some(cancel)) if !job.is_finished() => cancel.cancel(),
+            _ => return None,
+        }
+        if let Some(job) = self.job.take() {
+            // it ends with a "Cancelled" error, which nobody needs to hear about
+            job.await.ok();
+        }
+        self.cancel = None;
+        self.proceed = None;
+        self.progress
+            .take()
+            .map(|progress| progress.borrow().clone())
+    }
+
+    /// Lets a job waiting for filament carry on. `false` if no job is waiting.
+    pub fn proceed(&self) -> bool {
+        let waiting = self
+            .current_progress()
+            .is_some_and(|progress| progress.phase == Phase::WaitingForFilament);
+        match &self.proceed {
+            Some(proceed) if waiting => {
+                proceed.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `None` unless a job is running
+    pub fn current_progress(&self) -> Option<JobProgress> {
+        match &self.job {
+            Some(job) if !job.is_finished() => self
+                .progress
+                .as_ref()
+                .map(|progress| progress.borrow().clone()),
+            _ => None,
+        }
+    }
 }
 
-pub fn run_job<T>(task: T, long_running_job: &mut LongRunningJob) -> Result<(), AnyhowHTTPError>
+/// Starts `task` unless another job is still running.
+/// `task` gets the `Progress` to report to.
+pub fn run_job<F, T>(task: F, long_running_job: &mut LongRunningJob) -> Result<(), AnyhowHTTPError>
 where
+    F: FnOnce(Progress) -> T,
     T: Future<Output = anyhow::Result<String>> + Send + 'static,
 {
     match long_running_job.job {
@@ -39,7 +231,67 @@ where
         None => {}
     }
 
-    long_running_job.job = Some(tokio::spawn(task));
+    let progress = Progress::default();
+    let cancel = CancellationToken::new();
+    long_running_job.progress = Some(progress.subscribe());
+    long_running_job.cancel = Some(cancel.clone());
+    long_running_job.proceed = Some(progress.proceed.clone());
+
+    let task = task(progress);
+    long_running_job.job = Some(tokio::spawn(async move {
+        tokio::select! {
+            result = task => result,
+            _ = cancel.cancelled() => Err(anyhow!("Cancelled")),
+        }
+    }));
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel() {
+        let mut long_running_job = LongRunningJob::default();
+        assert!(long_running_job.cancel().await.is_none());
+
+        run_job(
+            |progress| async move {
+                progress.phase(Phase::Heating, 10);
+                // like waiting for a hot end that never heats up
+                std::future::pending::<()>().await;
+                Ok("Never finishes".to_string())
+            },
+            &mut long_running_job,
+        )
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(
+            long_running_job.current_progress().unwrap().phase,
+            Phase::Heating
+        );
+
+        assert!(!long_running_job.proceed(), "not waiting for filament");
+        assert!(long_running_job.cancel().await.is_some());
+        assert!(long_running_job.current_progress().is_none());
+        assert!(matches!(
+            long_running_job.take_status().await.unwrap(),
+            JobStatus::NoJob
+        ));
+
+        run_job(|_| async { Ok("Done".to_string()) }, &mut long_running_job).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(
+            long_running_job.cancel().await.is_none(),
+            "already finished"
+        );
+        assert!(matches!(
+            long_running_job.take_status().await.unwrap(),
+            JobStatus::Finished(message) if message == "Done"
+        ));
+    }
+}
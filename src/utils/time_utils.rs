@@ -1,3 +1,7 @@
+use std::str::FromStr;
+
+use chrono::NaiveTime;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Time {
     hours: u8,
@@ -32,3 +36,96 @@ impl Time {
         }
     }
 }
+
+/// A daily window like `22:00-07:00`. The end is exclusive, and windows may wrap past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// How long from `time` until the window ends (zero if `time` is outside of it)
+    pub fn remaining(&self, time: NaiveTime) -> std::time::Duration {
+        if !self.contains(time) {
+            return std::time::Duration::ZERO;
+        }
+        let mut remaining = self.end - time;
+        if remaining < chrono::Duration::zero() {
+            remaining = remaining + chrono::Duration::days(1);
+        }
+        remaining.to_std().unwrap_or_default()
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("{:?} should look like 22:00-07:00", s))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format!("{:?} is not a time like 07:00: {}", t.trim(), e))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_time_window() {
+        let day: TimeWindow = "08:00-22:00".parse().unwrap();
+        assert!(day.contains(at(8, 0)));
+        assert!(day.contains(at(21, 59)));
+        assert!(!day.contains(at(22, 0)));
+        assert!(!day.contains(at(3, 0)));
+
+        let night: TimeWindow = "22:00 - 07:00".parse().unwrap();
+        assert!(night.contains(at(23, 30)));
+        assert!(night.contains(at(3, 0)));
+        assert!(!night.contains(at(7, 0)));
+        assert!(!night.contains(at(12, 0)));
+
+        assert_eq!(
+            night.remaining(at(23, 0)),
+            std::time::Duration::from_secs(8 * 3600)
+        );
+        assert_eq!(
+            night.remaining(at(6, 30)),
+            std::time::Duration::from_secs(30 * 60)
+        );
+        assert_eq!(night.remaining(at(12, 0)), std::time::Duration::ZERO);
+
+        assert!("22:00".parse::<TimeWindow>().is_err());
+        assert!("25:00-07:00".parse::<TimeWindow>().is_err());
+    }
+}
@@ -0,0 +1,411 @@
+//! Runs `PrinterService` and the http routes against the mock OctoPrint
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, test, web, App};
+use printer_actions::auth::TokenStore;
+use printer_actions::config::{ConfirmConfig, ConfirmPolicy, ParkPosition};
+use printer_actions::confirmation::Confirmations;
+use printer_actions::data_defs::printer_job_action::PauseAction;
+use printer_actions::events::PrinterEvent;
+use printer_actions::filaments::FilamentProfiles;
+use printer_actions::history::PrintHistory;
+use printer_actions::idempotency::IdempotencyCache;
+use printer_actions::loaded_filament::LoadedFilaments;
+use printer_actions::mock_octoprint::{self, MockOctoPrint};
+use printer_actions::printer_registry::PrinterRegistry;
+use printer_actions::remote::moonraker_service::MoonrakerService;
+use printer_actions::remote::printer_service::PrinterService;
+use printer_actions::remote::prusalink_service::PrusaLinkService;
+use printer_actions::routes;
+use printer_actions::simulated_printer::SimStatus;
+use printer_actions::spools::Spools;
+use printer_actions::storage::Database;
+use printer_actions::traits::notify_trait::Notifier;
+use printer_actions::traits::printer_trait::Printer;
+use reqwest::Url;
+
+/// the mock OctoPrint's key, which only the server knows
+const KEY: &str = "secret";
+const ADMIN_TOKEN: &str = "admin-token-for-tests";
+/// an hour long print takes 3.6 s
+const SPEED: f64 = 1000.;
+
+async fn start_mock() -> (Arc<MockOctoPrint>, Url, PrinterService) {
+    let mock = Arc::new(MockOctoPrint::new(KEY, SPEED));
+    let (addr, server) =
+        mock_octoprint::start(mock.clone(), "127.0.0.1:0".parse().unwrap()).unwrap();
+    tokio::spawn(server);
+
+    let url = Url::parse(&format!("http://{}/api", addr)).unwrap();
+    let service = PrinterService::new(reqwest::Client::new(), &url);
+    (mock, url, service)
+}
+
+struct NoNotifier;
+
+#[async_trait::async_trait]
+impl Notifier for NoNotifier {
+    async fn notify(&self, _printer_id: &str, _event: &PrinterEvent) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[actix_web::test]
+async fn test_printer_service() {
+    let (mock, url, service) = start_mock().await;
+
+    let version = reqwest::Client::new()
+        .get(format!("{}/version", url))
+        .header("X-Api-Key", KEY)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(version.contains("mock"), "{}", version);
+    assert!(service.printer_state("wrong").await.is_err());
+
+    let state = service.printer_state(KEY).await.unwrap();
+    assert!(state.state.flags.operational && state.state.flags.ready);
+
+    service.start_job(KEY).await.unwrap();
+    assert!(service.start_job(KEY).await.is_err(), "already printing");
+
+    let job = service.job_state(KEY).await.unwrap();
+    assert_eq!(job.job.file.name.as_deref(), Some("benchy.gcode"));
+    assert_eq!(job.state, "Printing");
+
+    service.pause_job(KEY, PauseAction::Pause).await.unwrap();
+    assert!(service.printer_state(KEY).await.unwrap().state.flags.paused);
+    service.pause_job(KEY, PauseAction::Resume).await.unwrap();
+
+    service.cancel_job(KEY).await.unwrap();
+    assert!(
+        service
+            .printer_state(KEY)
+            .await
+            .unwrap()
+            .state
+            .flags
+            .cancelling
+    );
+    // cancelling takes a few simulated seconds
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(mock.printer.snapshot().status, SimStatus::Operational);
+    assert!(service.cancel_job(KEY).await.is_err(), "nothing to cancel");
+
+    mock.printer.fail("Thermal Runaway");
+    let state = service.printer_state(KEY).await.unwrap();
+    assert!(state.state.flags.error);
+    assert_eq!(state.state.error, "Thermal Runaway");
+    assert!(service.cool_down(KEY).await.is_err());
+}
+
+#[actix_web::test]
+async fn test_routes() {
+    let (mock, _, service) = start_mock().await;
+
+    let mut registry = PrinterRegistry::new("mock");
+    registry.insert(
+        "mock".to_string(),
+        Arc::new(service),
+        KEY.to_string(),
+        ParkPosition { x: 5., y: 200. },
+    );
+    // never reached, capabilities are checked before talking to the printer
+    let xl = PrusaLinkService::new(
+        reqwest::Client::new(),
+        &Url::parse("http://127.0.0.1:9").unwrap(),
+        "maker",
+        "password",
+    );
+    registry.insert(
+        "xl".to_string(),
+        Arc::new(xl),
+        KEY.to_string(),
+        ParkPosition::default(),
+    );
+    let voron = MoonrakerService::new(
+        reqwest::Client::new(),
+        &Url::parse("http://127.0.0.1:9").unwrap(),
+    );
+    registry.insert(
+        "voron".to_string(),
+        Arc::new(voron),
+        KEY.to_string(),
+        ParkPosition::default(),
+    );
+    let notifier: Arc<dyn Notifier> = Arc::new(NoNotifier);
+    let database = Arc::new(Database::open_in_memory().unwrap());
+    let history = PrintHistory::new(database.clone());
+    let loaded_filaments = LoadedFilaments::new(database.clone());
+    let spools = Spools::new(
+        database.clone(),
+        FilamentProfiles::default(),
+        Default::default(),
+    );
+    let tokens = TokenStore::new(database, Some(ADMIN_TOKEN.to_string()));
+    let confirmations = Confirmations::new(ConfirmConfig {
+        cancel: ConfirmPolicy::Always,
+        restart: ConfirmPolicy::Never,
+        timeout: Duration::from_secs(60),
+    });
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(registry))
+            .app_data(web::Data::from(notifier))
+            .app_data(web::Data::new(history))
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(confirmations))
+            .app_data(web::Data::new(IdempotencyCache::new()))
+            .app_data(web::Data::new(FilamentProfiles::default()))
+            .app_data(web::Data::new(loaded_filaments))
+            .app_data(web::Data::new(spools))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let call_with = |token: &str, method: &str, uri: &str| {
+        test::TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri(uri)
+            .insert_header(("X-Api-Key", token.to_string()))
+    };
+
+    let phone: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        call_with(ADMIN_TOKEN, "POST", "/tokens")
+            .set_json(serde_json::json!({
+                "name": "phone",
+                "scopes": ["readStatus", "jobControl", "filament"],
+            }))
+            .to_request(),
+    )
+    .await;
+    let phone_token = phone["token"].as_str().unwrap().to_string();
+    let call = |method: &str, uri: &str| call_with(&phone_token, method, uri).to_request();
+    let retried = |method: &str, uri: &str, key: &str| {
+        call_with(&phone_token, method, uri)
+            .insert_header(("Idempotency-Key", key.to_string()))
+            .to_request()
+    };
+
+    let body = test::call_and_read_body(&app, call("GET", "/job")).await;
+    assert_eq!(body, "Nothing is currently printing");
+
+    let spool: serde_json::Value = test::call_and_read_body_json(
+        &app,
+        call_with(&phone_token, "POST", "/spools")
+            .set_json(serde_json::json!({
+                "material": "pla",
+                "colour": "red",
+                "initialGrams": 1000,
+                "remainingGrams": 5,
+            }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(spool["material"], "PLA");
+    let uri = format!("/spool?id={}", spool["id"]);
+    let body = test::call_and_read_body(&app, call("POST", &uri)).await;
+    assert_eq!(body, "The red PLA spool is on mock now, with 5 grams left");
+
+    // the benchy needs about 6 g
+    let resp = test::call_service(&app, call("POST", "/job/start")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    assert!(
+        body.starts_with(
+            "benchy.gcode needs 6 grams of filament, but the red PLA spool only has 5"
+        ),
+        "{}",
+        body
+    );
+    let body = test::call_and_read_body(&app, call("POST", "/job/start?force=true")).await;
+    assert_eq!(body, "Starting to print benchy.gcode");
+
+    let body = test::call_and_read_body(&app, call("GET", "/printers/mock/job")).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        body.starts_with("Currently printing benchy.gcode"),
+        "{}",
+        body
+    );
+
+    let resp = test::call_service(&app, call("POST", "/job/resume")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(test::read_body(resp).await, "Nothing is currently paused");
+
+    let printing_at = [120., 110., 4.];
+    mock.printer.update(|state| state.head = printing_at);
+    let change = "/filament/change?filament=PETG";
+    let body = test::call_and_read_body(&app, retried("POST", change, "change-1")).await;
+    assert_eq!(body, "Job started");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let state = mock.printer.snapshot();
+    assert_eq!(state.status, SimStatus::Paused);
+    assert_eq!(state.head, [5., 200., 14.], "parked through G60 and G1");
+    // the retry isn't turned away because the first one paused the print
+    let body = test::call_and_read_body(&app, retried("POST", change, "change-1")).await;
+    assert_eq!(body, "Job started");
+    let body = test::call_and_read_body(&app, call("DELETE", "/filament/job")).await;
+    assert_eq!(body, "Stopped the filament job and cooling down");
+    let state = mock.printer.snapshot();
+    assert_eq!(state.status, SimStatus::Paused);
+    assert_eq!(
+        state.head, printing_at,
+        "the print head is back on the print"
+    );
+    let body = test::call_and_read_body(&app, call("POST", "/job/resume")).await;
+    assert_eq!(body, "Resuming print job");
+
+    let body = test::call_and_read_body(&app, call("POST", "/job/toggle-pause")).await;
+    assert_eq!(body, "Pausing print job");
+    assert_eq!(mock.printer.snapshot().status, SimStatus::Paused);
+
+    let resp = test::call_service(&app, call("DELETE", "/job")).await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let question: serde_json::Value = test::read_body_json(resp).await;
+    let message = question["message"].as_str().unwrap();
+    assert!(
+        message.starts_with("Cancel benchy.gcode at "),
+        "{}",
+        message
+    );
+    assert_eq!(mock.printer.snapshot().status, SimStatus::Paused);
+
+    let resp = test::call_service(&app, call("DELETE", "/job?confirm=guessed")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let uri = format!(
+        "/job?confirm={}",
+        question["confirmationToken"].as_str().unwrap()
+    );
+    let body = test::call_and_read_body(&app, retried("DELETE", &uri, "cancel-1")).await;
+    assert_eq!(body, "Cancelling print job");
+    // a retry gets the same answer, not a used up confirmation
+    let body = test::call_and_read_body(&app, retried("DELETE", &uri, "cancel-1")).await;
+    assert_eq!(body, "Cancelling print job");
+    // cancelling takes a few simulated seconds
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let body =
+        test::call_and_read_body(&app, retried("POST", "/filament?filament=PLA", "feed-1")).await;
+    assert_eq!(body, "Job started");
+    let body =
+        test::call_and_read_body(&app, retried("POST", "/filament?filament=PLA", "feed-1")).await;
+    assert_eq!(body, "Job started");
+    // names are looked up ignoring case
+    let resp = test::call_service(&app, call("POST", "/filament?filament=pla")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(test::read_body(resp).await, "Already running a job");
+
+    let resp = test::call_service(&app, call("POST", "/filament/continue")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = test::call_service(&app, call("POST", "/filament/change?filament=PETG")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(test::read_body(resp).await, "Nothing is currently printing");
+    let resp = test::call_service(&app, call("POST", "/filament/swap?filament=PETG")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let resp = test::call_service(&app, call("POST", "/filament?filament=Nylon")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        test::read_body(resp).await,
+        "No filament profile named Nylon"
+    );
+    let profiles: serde_json::Value =
+        test::call_and_read_body_json(&app, call("GET", "/filaments")).await;
+    assert_eq!(profiles[1]["name"], "PETG");
+    assert_eq!(profiles[1]["nozzleTemperature"], 230);
+
+    let resp = test::call_service(&app, call("DELETE", "/filament")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let loaded: serde_json::Value =
+        test::call_and_read_body_json(&app, call("GET", "/filament")).await;
+    assert!(loaded.is_null());
+    let body = test::call_and_read_body(&app, call("GET", "/filament/summary")).await;
+    assert_eq!(
+        body,
+        "No filament has been loaded since the last time it was unloaded"
+    );
+
+    // homing and parking are quick, then the temperature is polled every 10 s
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let info: serde_json::Value =
+        test::call_and_read_body_json(&app, call("GET", "/server-info")).await;
+    assert_eq!(info["job_status"], "Running", "{}", info);
+    assert_eq!(info["progress"]["phase"], "heating");
+    assert_eq!(info["progress"]["targetTemperature"], 200.);
+
+    let body = test::call_and_read_body(&app, call("GET", "/filament/status")).await;
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.starts_with("Heating the hot end, "), "{}", body);
+    assert!(body.ends_with("% done"), "{}", body);
+
+    let body = test::call_and_read_body(&app, call("DELETE", "/filament/job")).await;
+    assert_eq!(body, "Stopped the filament job and cooling down");
+    assert_eq!(mock.printer.snapshot().tool_target, 0.);
+    let body = test::call_and_read_body(&app, call("GET", "/filament/status")).await;
+    assert_eq!(body, "No filament job is running");
+    let resp = test::call_service(&app, call("DELETE", "/filament/job")).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let resp = test::call_service(&app, call("POST", "/printers/xl/filament?filament=PLA")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+    assert_eq!(
+        test::read_body(resp).await,
+        "Printer xl can't load or unload filament"
+    );
+
+    let resp = test::call_service(&app, call("POST", "/printers/voron/job/restart")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_IMPLEMENTED);
+    assert_eq!(
+        test::read_body(resp).await,
+        "Printer voron can't restart prints"
+    );
+
+    let capabilities: serde_json::Value =
+        test::call_and_read_body_json(&app, call("GET", "/printers/xl/capabilities")).await;
+    assert_eq!(capabilities["pause"], true);
+    assert_eq!(capabilities["rawExtrusion"], false);
+    assert_eq!(capabilities["restart"], false);
+
+    let printers: serde_json::Value =
+        test::call_and_read_body_json(&app, call("GET", "/printers")).await;
+    assert_eq!(printers[0]["id"], "mock");
+    assert_eq!(printers[0]["capabilities"]["rawExtrusion"], true);
+
+    let resp = test::call_service(&app, call("GET", "/printers/other/job")).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/job").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // the printer's own key doesn't work here anymore
+    let resp = test::call_service(&app, call_with(KEY, "GET", "/job").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = test::call_service(&app, call("GET", "/tokens")).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(
+        test::read_body(resp).await,
+        "Token phone isn't allowed to manage tokens"
+    );
+
+    let listed: serde_json::Value =
+        test::call_and_read_body_json(&app, call_with(ADMIN_TOKEN, "GET", "/tokens").to_request())
+            .await;
+    assert_eq!(listed[0]["name"], "phone");
+    assert!(listed[0].get("token").is_none());
+
+    let uri = format!("/tokens/{}", phone["id"]);
+    let body =
+        test::call_and_read_body(&app, call_with(ADMIN_TOKEN, "DELETE", &uri).to_request()).await;
+    assert_eq!(body, format!("Revoked token {}", phone["id"]));
+    let resp = test::call_service(&app, call("GET", "/job")).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}